@@ -6,23 +6,98 @@ pub struct Config {
     pub spotify_creds: Option<SpotifyCreds>,
     pub spotify_transfer: Option<SpotifyTransfer>,
     pub volume_sync: Option<VolumeSync>,
+    pub system_audio: Option<SystemAudio>,
+    pub usb_trigger: Option<UsbTrigger>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SpotifyCreds {
     pub spotify_client_id: String,
-    pub spotify_client_secret: String,
+    /// Not required when using PKCE
+    pub spotify_client_secret: Option<String>,
     pub spotify_redirect_uri: String,
+    /// Use the PKCE authorization flow, which doesn't require a client secret
+    #[serde(default)]
+    pub pkce: bool,
+    /// Keep the client secret (if not set above) and OAuth token in the OS
+    /// keyring, instead of in plaintext files
+    #[serde(default)]
+    pub keyring: bool,
+    /// Base URL of the Spotify Web API (e.g: to point at a mock server)
+    pub api_base_url: Option<String>,
+    /// Base URL of the Spotify accounts service, which handles logins (e.g: to
+    /// point at a mock server)
+    pub accounts_base_url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct SpotifyTransfer {
     pub spotify_name_remote: String,
     pub spotify_name_local: String,
+    /// How long to wait (in seconds) for the target device to come online
+    /// before giving up
+    pub device_wait_secs: Option<u64>,
+    /// Launch spotify on the remote computer (via its audio server) before
+    /// transferring playback to it
+    #[serde(default)]
+    pub wake_remote: bool,
+    /// What to do if nothing is playing when transferring playback
+    #[serde(default)]
+    pub when_idle: crate::controllers::spotify::WhenIdle,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct VolumeSync {
-    pub remote_host: String,
-    pub remote_port: u16,
+    /// Hostname of the remote computer. If omitted, the remote server is
+    /// discovered on the LAN (via mDNS).
+    pub remote_host: Option<String>,
+    /// Port to connect to (required if `remote_host` is set)
+    pub remote_port: Option<u16>,
+    /// Name of the remote server to look for when discovering servers on the
+    /// LAN (not required if there's only one)
+    pub remote_name: Option<String>,
+    /// Applications whose volume should be synced (e.g: `Spotify.exe`)
+    #[serde(default)]
+    pub sync_apps: Vec<String>,
+    /// Output device to switch the remote computer to when transferring audio
+    /// to it
+    pub output_device_remote: Option<String>,
+    /// Output device to switch the local computer to when transferring audio
+    /// to it
+    pub output_device_local: Option<String>,
+    /// Pre-shared key used to authenticate with the remote server (which must
+    /// be started using the same key)
+    pub psk: Option<String>,
+    /// SHA-256 fingerprint of the remote server's TLS certificate. If set, the
+    /// connection to the remote server is made over TLS.
+    pub tls_fingerprint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SystemAudio {
+    /// Which volume backend to use (defaults to `auto`)
+    pub backend: Option<crate::controllers::volume::Backend>,
+    /// Name or ID of the output device to control (defaults to the system's
+    /// default output device)
+    pub audio_device: Option<String>,
+    /// ALSA card to use when falling back to the ALSA mixer (e.g: `0`, `PCH`)
+    pub alsa_card: Option<String>,
+    /// ALSA mixer control to use (defaults to `Master`, then `PCM`)
+    pub alsa_control: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UsbTrigger {
+    /// USB device to watch for, as `VID:PID` (e.g: `1532:005C`)
+    pub usb_device: String,
+    /// Which computer to transfer audio to when the device is connected
+    pub on_connect: Option<crate::transfer::SyncAudioTo>,
+    /// Which computer to transfer audio to when the device is disconnected
+    pub on_disconnect: Option<crate::transfer::SyncAudioTo>,
+    /// Transfer spotify playback
+    #[serde(default)]
+    pub spotify: bool,
+    /// Sync volume (and mute state) between both computers
+    #[serde(default)]
+    pub sync_volume: bool,
 }
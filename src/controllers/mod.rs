@@ -1,3 +1,4 @@
 pub mod power;
 pub mod spotify;
+pub mod usb;
 pub mod volume;
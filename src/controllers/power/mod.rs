@@ -7,14 +7,12 @@ cfg_if::cfg_if! {
             pub struct PowerControllerImpl;
             impl PowerControllerImpl {
                 pub fn new_system_default() -> anyhow::Result<Self> {
-                    Err(anyhow::anyhow!("no volume controller is currently implemented for this platform. consider opening a PR?"))
+                    Ok(PowerControllerImpl)
                 }
 
-                pub fn get_master_volume(&self) -> anyhow::Result<f32> {
-                    Ok(0.0)
-                }
-
-                pub fn set_master_volume(&self, _vol: f32) -> anyhow::Result<()> {
+                pub fn wake_screen(&self) -> anyhow::Result<()> {
+                    // TODO: actually wake the screen on non-windows platforms
+                    log::debug!("waking the screen is not implemented on this platform");
                     Ok(())
                 }
             }
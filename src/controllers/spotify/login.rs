@@ -0,0 +1,361 @@
+//! Interactive Spotify login (i.e: the OAuth authorization code flow).
+//!
+//! If the redirect URI points at this machine (e.g:
+//! `http://127.0.0.1:8888/callback`), a tiny HTTP listener is run on its port
+//! to capture the authorization code directly. Otherwise, the user has to
+//! paste the URL they were redirected to into the console.
+
+use anyhow::anyhow;
+use anyhow::Context;
+use rspotify::clients::OAuthClient;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use url::Url;
+
+/// How long to wait for the user to finish logging in.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait on the browser to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bound on the size of the request headers we're willing to read.
+const MAX_REQUEST_LEN: usize = 16 * 1024;
+
+/// Walk the user through logging in (starting at `auth_url`), returning the
+/// authorization code.
+pub async fn get_code(spotify: &impl OAuthClient, auth_url: &str) -> anyhow::Result<String> {
+    let redirect_uri =
+        Url::parse(&spotify.get_oauth().redirect_uri).context("invalid redirect URI")?;
+
+    let listeners = if is_loopback(&redirect_uri) {
+        Some(Listeners::bind(&redirect_uri).await?)
+    } else {
+        None
+    };
+
+    match webbrowser::open(auth_url) {
+        Ok(_) => println!("Opened {} in your browser.", auth_url),
+        Err(e) => println!(
+            "Could not open a browser ({}). Please navigate here manually: {}",
+            e, auth_url
+        ),
+    }
+
+    match listeners {
+        Some(listeners) => {
+            log::info!("waiting for spotify to redirect to {}...", redirect_uri);
+            tokio::time::timeout(
+                LOGIN_TIMEOUT,
+                wait_for_code(spotify, &listeners, &redirect_uri),
+            )
+            .await
+            .map_err(|_| anyhow!("timed out waiting for spotify login"))?
+        }
+        None => {
+            log::info!(
+                "redirect URI doesn't point at this machine, so it has to be pasted in manually"
+            );
+            println!("Please enter the URL you were redirected to: ");
+            let input = tokio::task::spawn_blocking(|| {
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).map(|_| input)
+            })
+            .await??;
+            spotify
+                .parse_response_code(input.trim())
+                .ok_or_else(|| anyhow!("unable to parse the response code"))
+        }
+    }
+}
+
+fn is_loopback(url: &Url) -> bool {
+    url.scheme() == "http"
+        && match url.host() {
+            Some(url::Host::Domain(domain)) => domain.eq_ignore_ascii_case("localhost"),
+            Some(url::Host::Ipv4(addr)) => addr.is_loopback(),
+            Some(url::Host::Ipv6(addr)) => addr.is_loopback(),
+            None => false,
+        }
+}
+
+/// Listener(s) for the browser to be redirected to.
+struct Listeners(Vec<TcpListener>);
+
+impl Listeners {
+    /// Listen on the (loopback) host / port `redirect_uri` points at.
+    ///
+    /// `localhost` may resolve to either `127.0.0.1` or `::1`, depending on the
+    /// browser, so both are listened on.
+    async fn bind(redirect_uri: &Url) -> anyhow::Result<Listeners> {
+        let port = redirect_uri.port_or_known_default().unwrap_or(80);
+        let addrs: Vec<IpAddr> = match redirect_uri.host() {
+            Some(url::Host::Ipv4(addr)) => vec![addr.into()],
+            Some(url::Host::Ipv6(addr)) => vec![addr.into()],
+            _ => vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()],
+        };
+
+        let mut listeners = Vec::new();
+        let mut last_err = None;
+        for addr in addrs {
+            match TcpListener::bind((addr, port)).await {
+                Ok(listener) => {
+                    log::debug!("listening for the redirect on {}", listener.local_addr()?);
+                    listeners.push(listener);
+                }
+                // e.g: ipv6 being disabled
+                Err(e) => {
+                    log::debug!("could not listen on {}: {}", addr, e);
+                    last_err = Some(anyhow::Error::new(e).context(format!(
+                        "could not listen on {}",
+                        std::net::SocketAddr::new(addr, port)
+                    )));
+                }
+            }
+        }
+
+        match last_err {
+            Some(e) if listeners.is_empty() => Err(e),
+            _ => Ok(Listeners(listeners)),
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<TcpStream> {
+        std::future::poll_fn(|cx| {
+            for listener in &self.0 {
+                if let Poll::Ready(res) = listener.poll_accept(cx) {
+                    return Poll::Ready(res.map(|(socket, _)| socket));
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// Serve requests until the browser is redirected back to `redirect_uri`.
+async fn wait_for_code(
+    spotify: &impl OAuthClient,
+    listeners: &Listeners,
+    redirect_uri: &Url,
+) -> anyhow::Result<String> {
+    loop {
+        let mut socket = listeners.accept().await?;
+
+        let path = match tokio::time::timeout(REQUEST_TIMEOUT, read_request_path(&mut socket)).await
+        {
+            Ok(Ok(path)) => path,
+            Ok(Err(e)) => {
+                log::debug!("ignoring bad request: {:#}", e);
+                continue;
+            }
+            Err(_) => {
+                log::debug!("ignoring stalled request");
+                continue;
+            }
+        };
+
+        let url = match redirect_uri.join(&path) {
+            // browsers like to ask for favicons and such
+            Ok(url) if url.path() == redirect_uri.path() => url,
+            _ => {
+                let _ = respond(&mut socket, "404 Not Found", "Not found.").await;
+                continue;
+            }
+        };
+
+        if let Some((_, error)) = url.query_pairs().find(|(k, _)| k == "error") {
+            let _ = respond(&mut socket, "400 Bad Request", "Login failed.").await;
+            return Err(anyhow!("spotify login failed: {}", error));
+        }
+
+        // also checks that the `state` matches the one we sent
+        match spotify.parse_response_code(url.as_str()) {
+            Some(code) => {
+                let _ = respond(
+                    &mut socket,
+                    "200 OK",
+                    "Logged in to Spotify! You can close this tab now.",
+                )
+                .await;
+                return Ok(code);
+            }
+            None => {
+                let _ = respond(&mut socket, "400 Bad Request", "Invalid login response.").await;
+            }
+        }
+    }
+}
+
+/// Read an HTTP request's headers, returning the requested path (including the
+/// query string).
+async fn read_request_path(socket: &mut TcpStream) -> anyhow::Result<String> {
+    let mut buf = Vec::new();
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_LEN {
+            return Err(anyhow!("request too large"));
+        }
+
+        let mut chunk = [0; 1024];
+        let len = socket.read(&mut chunk).await?;
+        if len == 0 {
+            return Err(anyhow!("connection closed mid-request"));
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+
+    let request = String::from_utf8_lossy(&buf);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(path)) => Ok(path.to_string()),
+        _ => Err(anyhow!("unexpected request: {:?}", request.lines().next())),
+    }
+}
+
+async fn respond(socket: &mut TcpStream, status: &str, body: &str) -> anyhow::Result<()> {
+    let resp = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    socket.write_all(resp.as_bytes()).await?;
+    Ok(socket.shutdown().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rspotify::AuthCodeSpotify;
+    use rspotify::Credentials;
+    use rspotify::OAuth;
+
+    /// Listen for the redirect, returning the port being listened on, and the
+    /// eventual result.
+    async fn spawn_login() -> (u16, tokio::task::JoinHandle<anyhow::Result<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let redirect_uri = format!("http://127.0.0.1:{}/callback", port);
+
+        let spotify = AuthCodeSpotify::new(
+            Credentials::new("client-id", "client-secret"),
+            OAuth {
+                redirect_uri: redirect_uri.clone(),
+                state: "expected-state".into(),
+                ..Default::default()
+            },
+        );
+        let handle = tokio::spawn(async move {
+            let redirect_uri = Url::parse(&redirect_uri).unwrap();
+            wait_for_code(&spotify, &Listeners(vec![listener]), &redirect_uri).await
+        });
+        (port, handle)
+    }
+
+    /// Make a request, returning the response's status line.
+    async fn get(port: u16, request: &[u8]) -> String {
+        let mut socket = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        socket.write_all(request).await.unwrap();
+        let mut resp = String::new();
+        socket.read_to_string(&mut resp).await.unwrap();
+        resp.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn captures_code() {
+        let (port, login) = spawn_login().await;
+
+        let favicon = get(port, b"GET /favicon.ico HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert_eq!(favicon, "HTTP/1.1 404 Not Found");
+
+        // e.g: a stale tab from a previous login attempt
+        let wrong_state = get(
+            port,
+            b"GET /callback?code=abc&state=other-state HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await;
+        assert_eq!(wrong_state, "HTTP/1.1 400 Bad Request");
+
+        let ok = get(
+            port,
+            b"GET /callback?code=abc&state=expected-state HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await;
+        assert_eq!(ok, "HTTP/1.1 200 OK");
+        assert_eq!(login.await.unwrap().unwrap(), "abc");
+    }
+
+    #[tokio::test]
+    async fn reports_login_errors() {
+        let (port, login) = spawn_login().await;
+
+        let denied = get(
+            port,
+            b"GET /callback?error=access_denied&state=expected-state HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert_eq!(denied, "HTTP/1.1 400 Bad Request");
+
+        let err = login.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("access_denied"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let read_path = |request: Vec<u8>| {
+            let listener = &listener;
+            async move {
+                tokio::spawn(async move {
+                    let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                    // the client may be hung up on before it's done writing
+                    let _ = client.write_all(&request).await;
+                });
+                let (mut socket, _) = listener.accept().await.unwrap();
+                read_request_path(&mut socket).await
+            }
+        };
+
+        let path = read_path(b"GET /callback?code=abc HTTP/1.1\r\n\r\n".to_vec()).await;
+        assert_eq!(path.unwrap(), "/callback?code=abc");
+
+        let oversized = format!(
+            "GET / HTTP/1.1\r\nCookie: {}\r\n\r\n",
+            "a".repeat(MAX_REQUEST_LEN * 2)
+        );
+        let err = read_path(oversized.into_bytes()).await.unwrap_err();
+        assert!(err.to_string().contains("too large"), "{}", err);
+
+        assert!(read_path(b"POST /callback HTTP/1.1\r\n\r\n".to_vec())
+            .await
+            .is_err());
+        assert!(read_path(b"GET /callback HTTP/1.1\r\n".to_vec())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn listens_on_localhost() {
+        let listeners = Listeners::bind(&Url::parse("http://localhost:0/callback").unwrap())
+            .await
+            .unwrap();
+        assert!(listeners
+            .0
+            .iter()
+            .all(|l| l.local_addr().unwrap().ip().is_loopback()));
+        assert!(!listeners.0.is_empty());
+
+        let listeners = Listeners::bind(&Url::parse("http://127.0.0.1:0/callback").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(listeners.0.len(), 1);
+    }
+}
@@ -0,0 +1,532 @@
+//! In-process stand-in for the bits of the Spotify Web API (and accounts
+//! service) we use, so that [`SpotifyWrapper`](super::SpotifyWrapper) can be
+//! tested offline.
+//!
+//! Point `spotify_creds.api_base_url` / `accounts_base_url` at the mock (see
+//! [`creds`]), and seed the token cache with [`seed_token_cache`]. Logging in
+//! isn't supported (since that needs a browser), but refreshing tokens is.
+
+use crate::config;
+use rspotify::model::Device;
+use rspotify::model::DeviceType;
+use serde_json::json;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+
+const SCOPES: &str =
+    "user-modify-playback-state user-read-playback-state user-read-recently-played";
+
+const ACCESS_TOKEN: &str = "mock-access-token";
+const REFRESH_TOKEN: &str = "mock-refresh-token";
+
+#[derive(Debug, Default)]
+struct MockState {
+    /// The only access token API requests are accepted with
+    access_token: String,
+    /// The only refresh token which can currently be used
+    refresh_token: String,
+    /// How many times tokens have been refreshed
+    refreshes: u32,
+    /// Status to fail token requests with
+    token_failure: Option<&'static str>,
+    /// How many of the upcoming device listings to fail
+    device_failures: usize,
+    /// Recently played tracks (most recent first)
+    history: Vec<serde_json::Value>,
+    /// Device ID and body of the last request to start playback
+    last_play: Option<(String, serde_json::Value)>,
+    devices: Vec<Device>,
+    /// ID of the device playback is happening on (if there's an active session)
+    active_device: Option<String>,
+    is_playing: bool,
+    /// How long to wait before responding to each request
+    latency: Duration,
+    requests: Vec<String>,
+}
+
+/// A mock Spotify Web API server, running on a random local port.
+///
+/// Cloning a `MockSpotify` returns a handle to the _same_ server, so that it
+/// can be scripted / inspected while requests are being made against it.
+#[derive(Debug, Clone)]
+pub struct MockSpotify {
+    port: u16,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockSpotify {
+    /// Start a server with no devices, and nothing playing.
+    pub async fn spawn() -> MockSpotify {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mock = MockSpotify {
+            port: listener.local_addr().unwrap().port(),
+            state: Arc::new(Mutex::new(MockState {
+                access_token: ACCESS_TOKEN.into(),
+                refresh_token: REFRESH_TOKEN.into(),
+                ..Default::default()
+            })),
+        };
+
+        tokio::spawn({
+            let mock = mock.clone();
+            async move {
+                while let Ok((socket, _)) = listener.accept().await {
+                    tokio::spawn(mock.clone().serve(socket));
+                }
+            }
+        });
+
+        mock
+    }
+
+    pub fn api_base_url(&self) -> String {
+        format!("http://127.0.0.1:{}/v1/", self.port)
+    }
+
+    pub fn accounts_base_url(&self) -> String {
+        format!("http://127.0.0.1:{}/", self.port)
+    }
+
+    /// The refresh token the mock currently accepts.
+    pub fn refresh_token(&self) -> String {
+        self.state.lock().unwrap().refresh_token.clone()
+    }
+
+    /// Revoke the current refresh token (e.g: as if the user removed access
+    /// for the app).
+    pub fn revoke_refresh_token(&self) {
+        self.state.lock().unwrap().refresh_token = "revoked".into();
+    }
+
+    /// Fail all token requests with the given status (e.g: `503 Service
+    /// Unavailable`).
+    pub fn fail_token_requests(&self, status: &'static str) {
+        self.state.lock().unwrap().token_failure = Some(status);
+    }
+
+    /// Fail the next `n` device listings (e.g: as if spotify was having a
+    /// moment).
+    pub fn fail_devices(&self, n: usize) {
+        self.state.lock().unwrap().device_failures = n;
+    }
+
+    /// Bring a device online.
+    pub fn add_device(&self, id: &str, name: &str, volume_percent: u32) {
+        self.state.lock().unwrap().devices.push(Device {
+            id: Some(id.into()),
+            is_active: false,
+            is_private_session: false,
+            is_restricted: false,
+            name: name.into(),
+            _type: DeviceType::Computer,
+            volume_percent: Some(volume_percent),
+        });
+    }
+
+    /// Start playing on the given device.
+    pub fn play_on(&self, device_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.active_device = Some(device_id.into());
+        state.is_playing = true;
+    }
+
+    /// Add a track (with its context's URI, if it was played as part of one)
+    /// to the top of the recently played tracks.
+    pub fn add_history(&self, track_id: &str, context: Option<&str>) {
+        let context = context.map(|uri| {
+            json!({
+                "uri": uri,
+                "href": "",
+                "external_urls": {},
+                "type": uri.split(':').nth(1).unwrap(),
+            })
+        });
+        let item = json!({
+            "track": {
+                "album": {
+                    "album_type": "album",
+                    "artists": [],
+                    "external_urls": {},
+                    "href": null,
+                    "id": null,
+                    "images": [],
+                    "name": "Some Album",
+                },
+                "artists": [],
+                "disc_number": 1,
+                "duration_ms": 180000,
+                "explicit": false,
+                "external_ids": {},
+                "external_urls": {},
+                "href": null,
+                "id": track_id,
+                "is_local": false,
+                "name": "Some Track",
+                "popularity": 0,
+                "preview_url": null,
+                "track_number": 1,
+            },
+            "played_at": "2022-01-01T00:00:00Z",
+            "context": context,
+        });
+        self.state.lock().unwrap().history.insert(0, item);
+    }
+
+    /// Device ID and body of the last request to start playback.
+    pub fn last_play(&self) -> Option<(String, serde_json::Value)> {
+        self.state.lock().unwrap().last_play.clone()
+    }
+
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// ID of the device playback is happening on, and whether it's playing.
+    pub fn playback(&self) -> Option<(String, bool)> {
+        let state = self.state.lock().unwrap();
+        let device = state.active_device.clone()?;
+        Some((device, state.is_playing))
+    }
+
+    pub fn volume(&self, device_id: &str) -> Option<u32> {
+        let state = self.state.lock().unwrap();
+        state
+            .devices
+            .iter()
+            .find(|d| d.id.as_deref() == Some(device_id))?
+            .volume_percent
+    }
+
+    /// Every request made so far (e.g: `PUT /v1/me/player/volume`).
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    async fn serve(self, mut socket: TcpStream) {
+        let (method, target, auth, body) = match read_request(&mut socket).await {
+            Ok(req) => req,
+            Err(e) => {
+                log::debug!("mock spotify: bad request: {:#}", e);
+                return;
+            }
+        };
+
+        let latency = self.state.lock().unwrap().latency;
+        tokio::time::sleep(latency).await;
+
+        let (status, body) = self.handle(&method, &target, auth.as_deref(), &body);
+        let resp = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        let _ = socket.write_all(resp.as_bytes()).await;
+        let _ = socket.shutdown().await;
+    }
+
+    fn handle(
+        &self,
+        method: &str,
+        target: &str,
+        auth: Option<&str>,
+        body: &[u8],
+    ) -> (&'static str, String) {
+        let url = url::Url::parse("http://localhost")
+            .unwrap()
+            .join(target)
+            .unwrap();
+        let query = |key: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.into_owned())
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.requests.push(format!("{} {}", method, url.path()));
+
+        if url.path().starts_with("/v1/") {
+            let expected = format!("Bearer {}", state.access_token);
+            if auth != Some(expected.as_str()) {
+                return error("401 Unauthorized", 401, "Invalid access token");
+            }
+        }
+
+        match (method, url.path()) {
+            ("POST", "/api/token") => state.refresh(auth, body),
+            ("GET", "/v1/me/player/devices") if state.device_failures > 0 => {
+                state.device_failures -= 1;
+                error("503 Service Unavailable", 503, "Service unavailable")
+            }
+            ("GET", "/v1/me/player/devices") => {
+                let devices = state.devices_json();
+                ("200 OK", json!({ "devices": devices }).to_string())
+            }
+            ("GET", "/v1/me/player") => {
+                let device = match state.active_device() {
+                    Some(device) => device,
+                    None => return ("204 No Content", String::new()),
+                };
+                let playback = json!({
+                    "device": device,
+                    "repeat_state": "off",
+                    "shuffle_state": false,
+                    "context": null,
+                    "timestamp": 0,
+                    "progress_ms": 0,
+                    "is_playing": state.is_playing,
+                    "item": null,
+                    "currently_playing_type": "unknown",
+                    "actions": { "disallows": {} },
+                });
+                ("200 OK", playback.to_string())
+            }
+            ("PUT", "/v1/me/player") => {
+                let body: serde_json::Value = match serde_json::from_slice(body) {
+                    Ok(body) => body,
+                    Err(_) => return error("400 Bad Request", 400, "malformed json"),
+                };
+                let id = body["device_ids"][0].as_str().unwrap_or_default();
+                if !state.has_device(id) {
+                    return error("404 Not Found", 404, "Device not found");
+                }
+
+                state.active_device = Some(id.into());
+                if let Some(play) = body["play"].as_bool() {
+                    state.is_playing = play;
+                }
+                ("204 No Content", String::new())
+            }
+            ("GET", "/v1/me/player/recently-played") => {
+                let limit = query("limit").and_then(|v| v.parse().ok()).unwrap_or(20);
+                let items = state.history.iter().take(limit).collect::<Vec<_>>();
+                let page = json!({
+                    "href": url.as_str(),
+                    "items": items,
+                    "limit": limit,
+                    "next": null,
+                    "cursors": { "after": null },
+                });
+                ("200 OK", page.to_string())
+            }
+            ("PUT", "/v1/me/player/play") => {
+                let body: serde_json::Value = match serde_json::from_slice(body) {
+                    Ok(body) => body,
+                    Err(_) => return error("400 Bad Request", 400, "malformed json"),
+                };
+                let id = match query("device_id").or_else(|| state.active_device.clone()) {
+                    Some(id) if state.has_device(&id) => id,
+                    _ => return error("404 Not Found", 404, "Device not found"),
+                };
+
+                state.active_device = Some(id.clone());
+                state.is_playing = true;
+                state.last_play = Some((id, body));
+                ("204 No Content", String::new())
+            }
+            ("PUT", "/v1/me/player/volume") => {
+                let id = match query("device_id").or_else(|| state.active_device.clone()) {
+                    Some(id) if state.has_device(&id) => id,
+                    _ => return error("404 Not Found", 404, "Device not found"),
+                };
+                let volume = match query("volume_percent").and_then(|v| v.parse().ok()) {
+                    Some(volume @ 0..=100) => volume,
+                    _ => return error("400 Bad Request", 400, "Invalid volume"),
+                };
+
+                for device in &mut state.devices {
+                    if device.id.as_deref() == Some(id.as_str()) {
+                        device.volume_percent = Some(volume);
+                    }
+                }
+                ("204 No Content", String::new())
+            }
+            _ => error("404 Not Found", 404, "Service not found"),
+        }
+    }
+}
+
+impl MockState {
+    /// Handle a `refresh_token` grant. Like the real thing, only PKCE refresh
+    /// tokens (i.e: those of apps without a client secret) are rotated.
+    fn refresh(&mut self, auth: Option<&str>, body: &[u8]) -> (&'static str, String) {
+        if let Some(status) = self.token_failure {
+            return (status, String::new());
+        }
+
+        let form = url::form_urlencoded::parse(body).collect::<std::collections::HashMap<_, _>>();
+        let field = |key: &str| form.get(key).map(|v| v.as_ref());
+        let pkce = match (auth, field("client_id")) {
+            (Some(auth), None) if auth.starts_with("Basic ") => false,
+            (None, Some(_)) => true,
+            _ => return auth_error("invalid_client", "Invalid client"),
+        };
+        if field("grant_type") != Some("refresh_token") {
+            return auth_error("unsupported_grant_type", "Unsupported grant type");
+        }
+        if field("refresh_token") != Some(self.refresh_token.as_str()) {
+            return auth_error("invalid_grant", "Invalid refresh token");
+        }
+
+        self.refreshes += 1;
+        self.access_token = format!("{}-{}", ACCESS_TOKEN, self.refreshes);
+        let mut token = json!({
+            "access_token": self.access_token,
+            "token_type": "Bearer",
+            "expires_in": 3600,
+            "scope": SCOPES,
+        });
+        if pkce {
+            self.refresh_token = format!("{}-{}", REFRESH_TOKEN, self.refreshes);
+            token["refresh_token"] = json!(self.refresh_token);
+        }
+        ("200 OK", token.to_string())
+    }
+
+    fn has_device(&self, id: &str) -> bool {
+        self.devices.iter().any(|d| d.id.as_deref() == Some(id))
+    }
+
+    fn active_device(&self) -> Option<serde_json::Value> {
+        let id = self.active_device.as_deref()?;
+        let device = self.devices.iter().find(|d| d.id.as_deref() == Some(id))?;
+        Some(self.device_json(device))
+    }
+
+    fn devices_json(&self) -> Vec<serde_json::Value> {
+        self.devices.iter().map(|d| self.device_json(d)).collect()
+    }
+
+    fn device_json(&self, device: &Device) -> serde_json::Value {
+        let mut json = serde_json::to_value(device).unwrap();
+        json["is_active"] = json!(device.id == self.active_device);
+        json
+    }
+}
+
+/// A cached token, as written by
+/// [`SpotifyWrapper::login`](super::SpotifyWrapper::login), which won't expire
+/// any time soon.
+pub fn cached_token() -> serde_json::Value {
+    json!({
+        "access_token": ACCESS_TOKEN,
+        "expires_in": 3600,
+        "expires_at": "2100-01-01T00:00:00Z",
+        "refresh_token": REFRESH_TOKEN,
+        "scope": SCOPES,
+    })
+}
+
+/// Like [`cached_token`], but long since expired.
+pub fn expired_token() -> serde_json::Value {
+    let mut token = cached_token();
+    token["expires_at"] = json!("2000-01-01T00:00:00Z");
+    token
+}
+
+/// Write [`cached_token`] out to a fresh token cache, returning its path. It's
+/// up to the caller to clean it up.
+pub fn seed_token_cache() -> PathBuf {
+    seed_token_cache_with(&cached_token())
+}
+
+/// Write `token` out to a fresh token cache, returning its path.
+///
+/// Every call gets its own file, so that tests running in parallel don't
+/// trample each other's caches.
+pub fn seed_token_cache_with(token: &serde_json::Value) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "music-transfer-{}-{}-token.json",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&path, token.to_string()).unwrap();
+    path
+}
+
+/// Credentials for a (non-PKCE) app, which talk to `mock`.
+pub fn creds(mock: &MockSpotify) -> config::SpotifyCreds {
+    config::SpotifyCreds {
+        spotify_client_id: "some-client".into(),
+        spotify_client_secret: Some("hunter2".into()),
+        spotify_redirect_uri: "http://127.0.0.1:8888/callback".into(),
+        pkce: false,
+        keyring: false,
+        api_base_url: Some(mock.api_base_url()),
+        accounts_base_url: Some(mock.accounts_base_url()),
+    }
+}
+
+fn error(status: &'static str, code: u16, message: &str) -> (&'static str, String) {
+    let body = json!({ "error": { "status": code, "message": message } });
+    (status, body.to_string())
+}
+
+/// Errors from the accounts service are shaped differently from Web API ones.
+fn auth_error(error: &str, description: &str) -> (&'static str, String) {
+    let body = json!({ "error": error, "error_description": description });
+    ("400 Bad Request", body.to_string())
+}
+
+/// Read an HTTP request, returning its method, target, `Authorization` header,
+/// and body.
+async fn read_request(
+    socket: &mut TcpStream,
+) -> anyhow::Result<(String, String, Option<String>, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let header_len = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+
+        let mut chunk = [0; 1024];
+        let len = socket.read(&mut chunk).await?;
+        if len == 0 {
+            return Err(anyhow::anyhow!("connection closed mid-request"));
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    };
+
+    let headers = String::from_utf8_lossy(&buf[..header_len]).into_owned();
+    let mut lines = headers.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(anyhow::anyhow!("malformed request line")),
+    };
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .collect::<Vec<_>>();
+    let header = |key: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.trim())
+    };
+    let auth = header("authorization").map(String::from);
+    let content_len = header("content-length")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+
+    let mut body = buf.split_off(header_len);
+    while body.len() < content_len {
+        let mut chunk = [0; 1024];
+        let len = socket.read(&mut chunk).await?;
+        if len == 0 {
+            return Err(anyhow::anyhow!("connection closed mid-request"));
+        }
+        body.extend_from_slice(&chunk[..len]);
+    }
+
+    Ok((method, target, auth, body))
+}
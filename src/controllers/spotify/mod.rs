@@ -0,0 +1,959 @@
+use crate::config;
+use crate::secrets;
+use crate::secrets::SecretStore;
+use anyhow::anyhow;
+use anyhow::Context;
+use rspotify::clients::BaseClient;
+use rspotify::clients::OAuthClient;
+use rspotify::model::AlbumId;
+use rspotify::model::ArtistId;
+use rspotify::model::Id;
+use rspotify::model::Offset;
+use rspotify::model::PlayContextId;
+use rspotify::model::PlayHistory;
+use rspotify::model::PlayableId;
+use rspotify::model::PlaylistId;
+use rspotify::model::ShowId;
+use rspotify::model::Type;
+use rspotify::scopes;
+use rspotify::AuthCodePkceSpotify;
+use rspotify::AuthCodeSpotify;
+use rspotify::Credentials;
+use rspotify::OAuth;
+use rspotify::Token;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+mod login;
+#[cfg(test)]
+pub mod mock;
+mod token;
+mod volume;
+
+/// How long to wait for the target device to come online, unless configured
+/// otherwise.
+pub const DEFAULT_DEVICE_WAIT: Duration = Duration::from_secs(15);
+
+/// How long to wait before checking if the target device has come online.
+/// Doubles after each check, up to [`MAX_DEVICE_POLL_INTERVAL`].
+const MIN_DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+const MAX_DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(4);
+
+/// Returned when there's no usable cached token, and the user needs to run
+/// `music-transfer login` (again).
+#[derive(Debug)]
+pub struct LoginRequired(&'static str);
+
+impl std::fmt::Display for LoginRequired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}. run `music-transfer login` first", self.0)
+    }
+}
+
+impl std::error::Error for LoginRequired {}
+
+/// What to do when transferring playback while nothing is playing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ArgEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum WhenIdle {
+    /// Resume whatever was played most recently on the target device
+    Resume,
+    /// Make the target device the active device, but leave it paused
+    #[default]
+    Activate,
+}
+
+/// The underlying spotify client, depending on which auth flow is in use.
+enum Client {
+    AuthCode(AuthCodeSpotify),
+    /// Doesn't require a client secret
+    Pkce(AuthCodePkceSpotify),
+}
+
+/// Evaluate `$body` with `$client` bound to whichever client is in use.
+macro_rules! with_client {
+    ($spotify:expr, $client:ident => $body:expr) => {
+        match $spotify {
+            Client::AuthCode($client) => $body,
+            Client::Pkce($client) => $body,
+        }
+    };
+}
+
+impl volume::DeviceVolumeApi for Client {
+    async fn device_volume(&self, device_id: &str) -> anyhow::Result<Option<u8>> {
+        let devices = with_client!(self, c => c.device().await)?;
+        Ok(devices
+            .into_iter()
+            .find(|d| d.id.as_deref() == Some(device_id))
+            .and_then(|d| d.volume_percent)
+            .map(|v| v as u8))
+    }
+
+    async fn set_device_volume(&self, device_id: &str, volume_percent: u8) -> anyhow::Result<()> {
+        with_client!(self, c => c.volume(volume_percent, Some(device_id)).await)?;
+        Ok(())
+    }
+}
+
+pub struct SpotifyWrapper {
+    spotify: Client,
+    creds: config::SpotifyCreds,
+    /// Where secrets are kept when using the keyring
+    store: Arc<dyn SecretStore + Send + Sync>,
+}
+
+fn new_client(
+    cache_path: &str,
+    creds: &config::SpotifyCreds,
+    store: &dyn SecretStore,
+) -> anyhow::Result<Client> {
+    let oauth = OAuth {
+        redirect_uri: creds.spotify_redirect_uri.clone(),
+        scopes: scopes!(
+            "user-modify-playback-state",
+            "user-read-playback-state",
+            "user-read-recently-played"
+        ),
+        ..OAuth::default()
+    };
+    let config = rspotify::Config {
+        prefix: base_url(&creds.api_base_url, rspotify::DEFAULT_API_PREFIX),
+        cache_path: cache_path.into(),
+        // tokens stored in the keyring are loaded / saved by hand
+        token_cached: !creds.keyring,
+        // rspotify can't save refreshed tokens to the keyring (or hang on to
+        // rotated refresh tokens), so tokens are refreshed by hand too
+        token_refreshing: false,
+        ..rspotify::Config::default()
+    };
+
+    let client = if creds.pkce {
+        Client::Pkce(AuthCodePkceSpotify::with_config(
+            Credentials::new_pkce(&creds.spotify_client_id),
+            oauth,
+            config,
+        ))
+    } else {
+        let secret = match &creds.spotify_client_secret {
+            Some(secret) => secret.clone(),
+            None if creds.keyring => store
+                .get(&secrets::client_secret_key(&creds.spotify_client_id))?
+                .ok_or_else(|| {
+                    anyhow!("no spotify client secret in the OS keyring. run `music-transfer keyring migrate-secret` first")
+                })?,
+            None => {
+                return Err(anyhow!(
+                    r#"missing "spotify_client_secret" from config (required unless using "pkce")"#
+                ))
+            }
+        };
+        Client::AuthCode(AuthCodeSpotify::with_config(
+            Credentials::new(&creds.spotify_client_id, &secret),
+            oauth,
+            config,
+        ))
+    };
+    Ok(client)
+}
+
+/// The configured base URL (or `default`), with a trailing slash, since
+/// endpoint paths get blindly appended to it.
+fn base_url(url: &Option<String>, default: &str) -> String {
+    match url {
+        Some(url) if !url.ends_with('/') => format!("{}/", url),
+        Some(url) => url.clone(),
+        None => default.into(),
+    }
+}
+
+fn accounts_url(creds: &config::SpotifyCreds) -> String {
+    base_url(&creds.accounts_base_url, token::DEFAULT_ACCOUNTS_BASE_URL)
+}
+
+/// Read the cached token (ignoring any tokens which lack the required scopes).
+async fn read_token(
+    spotify: &mut impl OAuthClient,
+    creds: &config::SpotifyCreds,
+    store: &dyn SecretStore,
+) -> anyhow::Result<Option<Token>> {
+    if !creds.keyring {
+        // a missing / unreadable cache is no different from an empty one
+        return Ok(spotify.read_token_cache(true).await.ok().flatten());
+    }
+
+    let token = store
+        .get(&secrets::token_key(&creds.spotify_client_id))?
+        .and_then(|token| serde_json::from_str::<Token>(&token).ok())
+        .filter(|token| spotify.get_oauth().scopes.is_subset(&token.scopes));
+    Ok(token)
+}
+
+/// Save the client's current token, for use by future runs.
+async fn write_token(
+    spotify: &impl OAuthClient,
+    creds: &config::SpotifyCreds,
+    store: &dyn SecretStore,
+) -> anyhow::Result<()> {
+    if !creds.keyring {
+        return Ok(spotify.write_token_cache().await?);
+    }
+
+    let token = spotify.get_token();
+    let token = token.lock().await.unwrap();
+    if let Some(token) = token.as_ref() {
+        store.set(
+            &secrets::token_key(&creds.spotify_client_id),
+            &serde_json::to_string(token)?,
+        )?;
+    }
+    Ok(())
+}
+
+/// Load the cached token into `spotify`, refreshing it if it's expired.
+async fn load_cached_token(
+    spotify: &mut impl OAuthClient,
+    creds: &config::SpotifyCreds,
+    store: &dyn SecretStore,
+) -> anyhow::Result<()> {
+    let token = read_token(spotify, creds, store)
+        .await?
+        .ok_or(LoginRequired("not logged in to spotify"))?;
+    *spotify.get_token().lock().await.unwrap() = Some(token);
+
+    refresh_if_expired(spotify, creds, store).await
+}
+
+/// Refresh `spotify`'s token if it's expired (or about to be), saving the new
+/// one for use by future runs.
+async fn refresh_if_expired(
+    spotify: &impl OAuthClient,
+    creds: &config::SpotifyCreds,
+    store: &dyn SecretStore,
+) -> anyhow::Result<()> {
+    // the lock can't be held while saving the token
+    let token = spotify.get_token().lock().await.unwrap().clone();
+    let token = match token {
+        Some(token) if token.is_expired() => token,
+        _ => return Ok(()),
+    };
+
+    let token = token::refresh(spotify, &accounts_url(creds), &token).await?;
+    *spotify.get_token().lock().await.unwrap() = Some(token);
+    write_token(spotify, creds, store).await?;
+    log::info!("refreshed expired spotify token");
+    Ok(())
+}
+
+impl SpotifyWrapper {
+    /// Connect to spotify using the token cached by [`SpotifyWrapper::login`]
+    /// (refreshing it if it's expired).
+    ///
+    /// This never prompts the user for anything, and fails with
+    /// [`LoginRequired`] if there's no usable cached token.
+    pub async fn new(
+        cache_path: &str,
+        creds: &config::SpotifyCreds,
+    ) -> anyhow::Result<SpotifyWrapper> {
+        SpotifyWrapper::with_store(cache_path, creds, Arc::new(secrets::Keyring)).await
+    }
+
+    /// Like [`SpotifyWrapper::new`], but using `store` in place of the OS
+    /// keyring.
+    async fn with_store(
+        cache_path: &str,
+        creds: &config::SpotifyCreds,
+        store: Arc<dyn SecretStore + Send + Sync>,
+    ) -> anyhow::Result<SpotifyWrapper> {
+        let mut spotify = new_client(cache_path, creds, &*store)?;
+        with_client!(&mut spotify, c => load_cached_token(c, creds, &*store).await)?;
+        Ok(SpotifyWrapper {
+            spotify,
+            creds: creds.clone(),
+            store,
+        })
+    }
+
+    /// Interactively log in to spotify, caching the resulting token for use
+    /// by [`SpotifyWrapper::new`].
+    pub async fn login(
+        cache_path: &str,
+        creds: &config::SpotifyCreds,
+    ) -> anyhow::Result<SpotifyWrapper> {
+        let store = Arc::new(secrets::Keyring);
+        let mut spotify = new_client(cache_path, creds, &*store)?;
+
+        let accounts_url = accounts_url(creds);
+        let auth_url = match &mut spotify {
+            Client::AuthCode(c) => c.get_authorize_url(false)?,
+            // also generates the PKCE code verifier
+            Client::Pkce(c) => c.get_authorize_url(None)?,
+        };
+        let auth_url = token::authorize_url(&accounts_url, &auth_url);
+        let code = with_client!(&spotify, c => login::get_code(c, &auth_url).await)?;
+
+        let verifier = match &spotify {
+            Client::AuthCode(_) => None,
+            Client::Pkce(c) => c.verifier.clone(),
+        };
+        with_client!(&spotify, c => {
+            let token = token::request(c, &accounts_url, &code, verifier.as_deref())
+                .await
+                .context("could not get spotify token")?;
+            *c.get_token().lock().await.unwrap() = Some(token);
+            write_token(c, creds, &*store).await?;
+        });
+
+        Ok(SpotifyWrapper {
+            spotify,
+            creds: creds.clone(),
+            store,
+        })
+    }
+
+    /// Refresh the token if it's expired (which it will be, every hour or so,
+    /// for long-running commands like `watch`).
+    async fn refresh_token(&self) -> anyhow::Result<()> {
+        with_client!(&self.spotify, c => {
+            refresh_if_expired(c, &self.creds, &*self.store).await
+        })
+    }
+
+    pub async fn devices(&self) -> anyhow::Result<Vec<DeviceNormalized>> {
+        self.refresh_token().await?;
+        let mut devices = Vec::new();
+        for d in with_client!(&self.spotify, c => c.device().await)? {
+            devices.push(d.normalize()?)
+        }
+        Ok(devices)
+    }
+
+    /// The device spotify is currently playing on (if there's an active
+    /// session).
+    pub async fn active_device(&self) -> anyhow::Result<Option<DeviceNormalized>> {
+        self.refresh_token().await?;
+        let playback = with_client!(&self.spotify, c => {
+            c.current_playback(None, None::<std::slice::Iter<'_, _>>).await
+        })?;
+        playback.map(|p| p.device.normalize()).transpose()
+    }
+
+    /// Wait (for up to `timeout`) for the device with the given name to come
+    /// online.
+    ///
+    /// Failures to list devices are retried until the deadline too, in which
+    /// case the last failure is returned.
+    pub async fn wait_for_device(
+        &self,
+        name: &str,
+        timeout: Duration,
+    ) -> anyhow::Result<DeviceNormalized> {
+        let start = Instant::now();
+        let mut interval = MIN_DEVICE_POLL_INTERVAL;
+        loop {
+            let last_err = match self.devices().await {
+                Ok(devices) => match devices.into_iter().find(|d| d.name == name) {
+                    Some(device) => return Ok(device),
+                    None => None,
+                },
+                Err(e) => {
+                    log::warn!("could not list spotify devices: {:#}", e);
+                    Some(e)
+                }
+            };
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(match last_err {
+                    Some(e) => e.context(format!("could not check if {} is online", name)),
+                    None => anyhow!(
+                        "target device is not online (waited {}s for {})",
+                        timeout.as_secs(),
+                        name
+                    ),
+                });
+            }
+
+            log::info!(
+                "waiting for {} to come online ({}s / {}s)...",
+                name,
+                elapsed.as_secs(),
+                timeout.as_secs()
+            );
+            tokio::time::sleep(interval.min(timeout - elapsed)).await;
+            interval = (interval * 2).min(MAX_DEVICE_POLL_INTERVAL);
+        }
+    }
+
+    /// Transfer playback to the device with the given name, waiting (for up to
+    /// `device_wait`) for it to come online if needed.
+    pub async fn transfer_playback(
+        &mut self,
+        target_device_name: &str,
+        sync_volume: bool,
+        device_wait: Duration,
+        when_idle: WhenIdle,
+    ) -> anyhow::Result<()> {
+        self.refresh_token().await?;
+        let target_device = self
+            .wait_for_device(target_device_name, device_wait)
+            .await?;
+
+        let current_playback = with_client!(&self.spotify, c => {
+            c.current_playback(None, None::<std::slice::Iter<'_, _>>).await
+        })?;
+
+        let current_device = match current_playback {
+            Some(playback) => playback.device.normalize()?,
+            // no active session, so there's no playback (or volume) to transfer
+            None => return self.start_idle_playback(&target_device, when_idle).await,
+        };
+
+        if current_device.id == target_device.id {
+            log::warn!("attempting to transfer playback to current device - doing nothing");
+            return Ok(());
+        }
+
+        log::info!(
+            "transferring playback from {} to {}",
+            current_device.name,
+            target_device.name
+        );
+
+        with_client!(&self.spotify, c => c.transfer_playback(&target_device.id, None).await)?;
+
+        if sync_volume {
+            log::info!(
+                "matching volume from {} to {}",
+                current_device.name,
+                target_device.name
+            );
+
+            // playback only just moved, so the target device may not accept volume
+            // changes right away
+            match volume::set_volume(
+                &self.spotify,
+                &target_device.id,
+                current_device.volume_percent,
+            )
+            .await
+            {
+                Ok(attempts) => log::info!(
+                    "set volume of {} to {}% (after {} attempt(s))",
+                    target_device.name,
+                    current_device.volume_percent,
+                    attempts
+                ),
+                // playback has already been transferred, so don't fail the whole thing
+                Err(e) => log::warn!("could not set volume of {}: {:#}", target_device.name, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get `target_device` going when there's no active session to transfer.
+    async fn start_idle_playback(
+        &self,
+        target_device: &DeviceNormalized,
+        when_idle: WhenIdle,
+    ) -> anyhow::Result<()> {
+        if when_idle == WhenIdle::Resume {
+            let recent = with_client!(&self.spotify, c => {
+                c.current_user_recently_played(Some(1), None).await
+            })?;
+
+            match recent.items.into_iter().next() {
+                Some(history) => return self.resume_history(target_device, history).await,
+                None => log::warn!("nothing was played recently, so there's nothing to resume"),
+            }
+        }
+
+        log::info!(
+            "nothing is playing. activating {} (paused)",
+            target_device.name
+        );
+        with_client!(&self.spotify, c => {
+            c.transfer_playback(&target_device.id, Some(false)).await
+        })?;
+
+        Ok(())
+    }
+
+    /// Resume playing `history` (in its original context, if it had one) on
+    /// `target_device`.
+    async fn resume_history(
+        &self,
+        target_device: &DeviceNormalized,
+        history: PlayHistory,
+    ) -> anyhow::Result<()> {
+        let track = history
+            .track
+            .id
+            .ok_or_else(|| anyhow!("most recently played track is a local file"))?;
+
+        log::info!(
+            "nothing is playing. resuming {} on {}",
+            history.track.name,
+            target_device.name
+        );
+
+        let device = Some(target_device.id.as_str());
+        // start from the track, rather than the top of the playlist / album
+        let offset = Some(Offset::Uri(track.uri()));
+        let context = history.context.map(|c| (c._type, c.uri));
+        match context {
+            Some((Type::Album, uri)) => {
+                let context = AlbumId::from_uri(&uri)?;
+                self.start_context(&context, device, offset).await
+            }
+            Some((Type::Playlist, uri)) => {
+                let context = PlaylistId::from_uri(&uri)?;
+                self.start_context(&context, device, offset).await
+            }
+            // artist / show contexts don't support offsets
+            Some((Type::Artist, uri)) => {
+                let context = ArtistId::from_uri(&uri)?;
+                self.start_context(&context, device, None).await
+            }
+            Some((Type::Show, uri)) => {
+                let context = ShowId::from_uri(&uri)?;
+                self.start_context(&context, device, None).await
+            }
+            _ => {
+                with_client!(&self.spotify, c => {
+                    let uris = [&track as &dyn PlayableId];
+                    c.start_uris_playback(uris, device, None, None).await
+                })?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn start_context(
+        &self,
+        context: &impl PlayContextId,
+        device_id: Option<&str>,
+        offset: Option<Offset>,
+    ) -> anyhow::Result<()> {
+        with_client!(&self.spotify, c => {
+            c.start_context_playback(context, device_id, offset, None).await
+        })?;
+        Ok(())
+    }
+}
+
+/// Launch the spotify client on this machine (or bring it to the foreground,
+/// if it's already running), so that it shows up as a device.
+pub fn launch_client() -> anyhow::Result<()> {
+    #[cfg(windows)]
+    let mut cmd = {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+        let mut cmd = std::process::Command::new("cmd");
+        cmd.args(["/C", "start", "", "spotify:"])
+            .creation_flags(CREATE_NO_WINDOW);
+        cmd
+    };
+    #[cfg(target_os = "macos")]
+    let mut cmd = {
+        let mut cmd = std::process::Command::new("open");
+        cmd.arg("spotify:");
+        cmd
+    };
+    #[cfg(not(any(windows, target_os = "macos")))]
+    let mut cmd = {
+        let mut cmd = std::process::Command::new("xdg-open");
+        cmd.arg("spotify:");
+        cmd
+    };
+
+    let status = cmd.status().context("could not launch spotify")?;
+    if !status.success() {
+        return Err(anyhow!("could not launch spotify ({})", status));
+    }
+    Ok(())
+}
+
+// the rspotify device object assumes some fields can be nullable, when they
+// really can't
+//
+// (some fields are only ever read via the `Debug` impl, by
+// `list-spotify-devices`)
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct DeviceNormalized {
+    pub id: String,
+    pub is_active: bool,
+    pub is_private_session: bool,
+    pub is_restricted: bool,
+    pub name: String,
+    pub _type: rspotify::model::DeviceType,
+    pub volume_percent: u8,
+}
+
+trait SpotifyNormalize {
+    type Normalized;
+    fn normalize(self) -> anyhow::Result<Self::Normalized>;
+}
+
+impl SpotifyNormalize for rspotify::model::Device {
+    type Normalized = DeviceNormalized;
+
+    fn normalize(self) -> anyhow::Result<DeviceNormalized> {
+        Ok(DeviceNormalized {
+            id: self
+                .id
+                .ok_or_else(|| anyhow!("/me/player/devices returned device with None id"))?,
+            is_active: self.is_active,
+            is_private_session: self.is_private_session,
+            is_restricted: self.is_restricted,
+            name: self.name,
+            _type: self._type,
+            volume_percent: self.volume_percent.ok_or_else(|| {
+                anyhow!("/me/player/devices returned device with None volume_percent")
+            })? as u8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockSpotify;
+    use super::*;
+
+    /// Connect to `mock`, using a freshly seeded token cache.
+    async fn connect(mock: &MockSpotify) -> SpotifyWrapper {
+        let cache_path = mock::seed_token_cache();
+        let spotify = SpotifyWrapper::new(cache_path.to_str().unwrap(), &mock::creds(mock))
+            .await
+            .unwrap();
+        std::fs::remove_file(cache_path).unwrap();
+        spotify
+    }
+
+    /// Connect using `creds`, starting off with `token` cached, returning
+    /// whatever token ends up cached.
+    async fn connect_with(
+        creds: &config::SpotifyCreds,
+        token: serde_json::Value,
+    ) -> (anyhow::Result<SpotifyWrapper>, serde_json::Value) {
+        let cache_path = mock::seed_token_cache_with(&token);
+        let res = SpotifyWrapper::new(cache_path.to_str().unwrap(), creds).await;
+        let cached = std::fs::read_to_string(&cache_path).unwrap();
+        std::fs::remove_file(cache_path).unwrap();
+        (res, serde_json::from_str(&cached).unwrap())
+    }
+
+    #[tokio::test]
+    async fn refreshes_expired_token() {
+        let mock = MockSpotify::spawn().await;
+        mock.add_device("local-id", "local", 30);
+
+        let (spotify, cached) = connect_with(&mock::creds(&mock), mock::expired_token()).await;
+        let spotify = spotify.unwrap();
+
+        // the new access token is saved, and the refresh token is kept (since
+        // spotify doesn't hand out a new one to non-PKCE apps)
+        assert_eq!(cached["access_token"], "mock-access-token-1");
+        assert_eq!(cached["refresh_token"], mock.refresh_token());
+        // ...and actually used
+        assert_eq!(spotify.devices().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_rotated_pkce_refresh_token() {
+        let mock = MockSpotify::spawn().await;
+        let creds = config::SpotifyCreds {
+            spotify_client_secret: None,
+            pkce: true,
+            ..mock::creds(&mock)
+        };
+
+        let (spotify, cached) = connect_with(&creds, mock::expired_token()).await;
+        spotify.unwrap();
+        assert_eq!(cached["refresh_token"], "mock-refresh-token-1");
+        assert_eq!(cached["refresh_token"], mock.refresh_token());
+
+        // the old refresh token is dead, but the new one still works
+        let (res, _) = connect_with(&creds, mock::expired_token()).await;
+        assert!(res.err().unwrap().is::<LoginRequired>());
+
+        let mut token = cached;
+        token["expires_at"] = mock::expired_token()["expires_at"].clone();
+        let (spotify, cached) = connect_with(&creds, token).await;
+        spotify.unwrap();
+        assert_eq!(cached["refresh_token"], "mock-refresh-token-2");
+    }
+
+    #[tokio::test]
+    async fn login_required_when_session_cant_be_refreshed() {
+        let mock = MockSpotify::spawn().await;
+        let creds = mock::creds(&mock);
+
+        let mut token = mock::expired_token();
+        token["refresh_token"] = serde_json::Value::Null;
+        let (res, _) = connect_with(&creds, token).await;
+        assert!(res.err().unwrap().is::<LoginRequired>());
+
+        mock.revoke_refresh_token();
+        let (res, cached) = connect_with(&creds, mock::expired_token()).await;
+        assert!(res.err().unwrap().is::<LoginRequired>());
+        assert_eq!(cached, mock::expired_token());
+    }
+
+    #[tokio::test]
+    async fn refresh_failures_arent_login_required() {
+        let mock = MockSpotify::spawn().await;
+        mock.fail_token_requests("503 Service Unavailable");
+
+        let (res, _) = connect_with(&mock::creds(&mock), mock::expired_token()).await;
+        let err = res.err().unwrap();
+        assert!(!err.is::<LoginRequired>(), "{:#}", err);
+        assert!(
+            format!("{:#}", err).contains("could not refresh spotify session"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn keyring_tokens() {
+        let mock = MockSpotify::spawn().await;
+        let creds = config::SpotifyCreds {
+            keyring: true,
+            ..mock::creds(&mock)
+        };
+        let store = secrets::MemoryStore::default();
+        let key = secrets::token_key(&creds.spotify_client_id);
+        let mut client = new_client("unused", &creds, &store).unwrap();
+
+        async fn read(
+            client: &mut Client,
+            creds: &config::SpotifyCreds,
+            store: &dyn SecretStore,
+        ) -> Option<Token> {
+            with_client!(client, c => read_token(c, creds, store).await).unwrap()
+        }
+        assert_eq!(read(&mut client, &creds, &store).await, None);
+
+        store.set(&key, "not a token").unwrap();
+        assert_eq!(read(&mut client, &creds, &store).await, None);
+
+        // e.g: from before `user-read-recently-played` was needed
+        let mut token = mock::cached_token();
+        token["scope"] = "user-modify-playback-state user-read-playback-state".into();
+        store.set(&key, &token.to_string()).unwrap();
+        assert_eq!(read(&mut client, &creds, &store).await, None);
+
+        store.set(&key, &mock::cached_token().to_string()).unwrap();
+        let token = read(&mut client, &creds, &store).await.unwrap();
+        assert_eq!(token.access_token, "mock-access-token");
+
+        let mut token = token;
+        token.access_token = "some-other-token".into();
+        with_client!(&client, c => {
+            *c.get_token().lock().await.unwrap() = Some(token.clone());
+            write_token(c, &creds, &store).await.unwrap();
+        });
+        let saved = serde_json::from_str::<Token>(&store.get(&key).unwrap().unwrap()).unwrap();
+        assert_eq!(saved, token);
+    }
+
+    #[tokio::test]
+    async fn saves_tokens_refreshed_mid_run() {
+        let mock = MockSpotify::spawn().await;
+        mock.add_device("local-id", "local", 30);
+        let creds = config::SpotifyCreds {
+            keyring: true,
+            ..mock::creds(&mock)
+        };
+        let store = Arc::new(secrets::MemoryStore::default());
+        let key = secrets::token_key(&creds.spotify_client_id);
+        store.set(&key, &mock::cached_token().to_string()).unwrap();
+
+        let spotify = SpotifyWrapper::with_store("unused", &creds, store.clone())
+            .await
+            .unwrap();
+        assert_eq!(spotify.devices().await.unwrap().len(), 1);
+
+        // an hour later...
+        with_client!(&spotify.spotify, c => {
+            let token = c.get_token();
+            let mut token = token.lock().await.unwrap();
+            token.as_mut().unwrap().expires_at = Some(chrono::Utc::now());
+        });
+        assert_eq!(spotify.devices().await.unwrap().len(), 1);
+
+        let saved = serde_json::from_str::<Token>(&store.get(&key).unwrap().unwrap()).unwrap();
+        assert_eq!(saved.access_token, "mock-access-token-1");
+        assert!(!saved.is_expired());
+    }
+
+    #[tokio::test]
+    async fn transfers_playback_and_volume() {
+        let mock = MockSpotify::spawn().await;
+        mock.add_device("local-id", "local", 30);
+        mock.add_device("remote-id", "remote", 80);
+        mock.play_on("local-id");
+        mock.set_latency(Duration::from_millis(20));
+
+        let mut spotify = connect(&mock).await;
+        spotify
+            .transfer_playback("remote", true, Duration::from_secs(1), WhenIdle::Activate)
+            .await
+            .unwrap();
+
+        assert_eq!(mock.playback(), Some(("remote-id".into(), true)));
+        assert_eq!(mock.volume("remote-id"), Some(30));
+        assert!(mock
+            .requests()
+            .contains(&"PUT /v1/me/player/volume".to_string()));
+    }
+
+    #[tokio::test]
+    async fn waits_for_device_to_come_online() {
+        let mock = MockSpotify::spawn().await;
+        mock.add_device("local-id", "local", 30);
+        mock.play_on("local-id");
+
+        let mut spotify = connect(&mock).await;
+        let err = spotify
+            .transfer_playback("remote", false, Duration::from_secs(1), WhenIdle::Activate)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not online"), "{}", err);
+
+        tokio::spawn({
+            let mock = mock.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(700)).await;
+                mock.add_device("remote-id", "remote", 80);
+            }
+        });
+        spotify
+            .transfer_playback("remote", false, Duration::from_secs(5), WhenIdle::Activate)
+            .await
+            .unwrap();
+        assert_eq!(mock.playback(), Some(("remote-id".into(), true)));
+    }
+
+    #[tokio::test]
+    async fn keeps_waiting_through_errors() {
+        let mock = MockSpotify::spawn().await;
+        mock.add_device("remote-id", "remote", 80);
+        let spotify = connect(&mock).await;
+
+        mock.fail_devices(2);
+        let device = spotify
+            .wait_for_device("remote", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(device.id, "remote-id");
+
+        // the last error is reported once time's up
+        mock.fail_devices(usize::MAX);
+        let err = spotify
+            .wait_for_device("remote", Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(
+            err.contains("could not check if remote is online"),
+            "{}",
+            err
+        );
+        assert!(err.contains("503"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn activates_target_when_idle() {
+        let mock = MockSpotify::spawn().await;
+        mock.add_device("remote-id", "remote", 80);
+
+        let mut spotify = connect(&mock).await;
+        spotify
+            .transfer_playback("remote", true, Duration::from_secs(1), WhenIdle::Activate)
+            .await
+            .unwrap();
+
+        assert_eq!(mock.playback(), Some(("remote-id".into(), false)));
+        // nothing to match the volume to
+        assert_eq!(mock.volume("remote-id"), Some(80));
+    }
+
+    /// Transfer playback to `remote` (with nothing playing), resuming whatever
+    /// was played last.
+    async fn resume(mock: &MockSpotify) {
+        let mut spotify = connect(mock).await;
+        spotify
+            .transfer_playback("remote", true, Duration::from_secs(1), WhenIdle::Resume)
+            .await
+            .unwrap();
+    }
+
+    async fn idle_mock() -> MockSpotify {
+        let mock = MockSpotify::spawn().await;
+        mock.add_device("remote-id", "remote", 80);
+        mock.add_history("0eGsygTp906u18L0Oimnem", None);
+        mock
+    }
+
+    #[tokio::test]
+    async fn resumes_album() {
+        let mock = idle_mock().await;
+        mock.add_history(
+            "4uLU6hMCjMI75M1A2tKUQC",
+            Some("spotify:album:6akEvsycLGftJxYudPjmqK"),
+        );
+        resume(&mock).await;
+
+        assert_eq!(mock.playback(), Some(("remote-id".into(), true)));
+        let body = serde_json::json!({
+            "context_uri": "spotify:album:6akEvsycLGftJxYudPjmqK",
+            "offset": { "uri": "spotify:track:4uLU6hMCjMI75M1A2tKUQC" },
+        });
+        assert_eq!(mock.last_play(), Some(("remote-id".into(), body)));
+    }
+
+    #[tokio::test]
+    async fn resumes_playlist() {
+        let mock = idle_mock().await;
+        mock.add_history(
+            "4uLU6hMCjMI75M1A2tKUQC",
+            Some("spotify:playlist:37i9dQZF1DXcBWIGoYBM5M"),
+        );
+        resume(&mock).await;
+
+        let body = serde_json::json!({
+            "context_uri": "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M",
+            "offset": { "uri": "spotify:track:4uLU6hMCjMI75M1A2tKUQC" },
+        });
+        assert_eq!(mock.last_play(), Some(("remote-id".into(), body)));
+    }
+
+    #[tokio::test]
+    async fn resumes_track_without_context() {
+        let mock = idle_mock().await;
+        mock.add_history("4uLU6hMCjMI75M1A2tKUQC", None);
+        resume(&mock).await;
+
+        let body = serde_json::json!({ "uris": ["spotify:track:4uLU6hMCjMI75M1A2tKUQC"] });
+        assert_eq!(mock.last_play(), Some(("remote-id".into(), body)));
+    }
+
+    #[tokio::test]
+    async fn resume_activates_target_without_history() {
+        let mock = MockSpotify::spawn().await;
+        mock.add_device("remote-id", "remote", 80);
+        resume(&mock).await;
+
+        assert_eq!(mock.last_play(), None);
+        assert_eq!(mock.playback(), Some(("remote-id".into(), false)));
+    }
+}
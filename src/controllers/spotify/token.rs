@@ -0,0 +1,196 @@
+//! Fetching / refreshing OAuth tokens from the spotify accounts service.
+//!
+//! rspotify can do this by itself, but it always talks to
+//! `accounts.spotify.com` (which rules out testing against a mock server).
+//! Also, when using PKCE, it throws away the refresh token spotify hands back,
+//! and holds on to the old one. Spotify rotates PKCE refresh tokens (i.e: each
+//! one only works once), so the next refresh would fail, logging the user out.
+
+use super::LoginRequired;
+use chrono::Utc;
+use rspotify::clients::BaseClient;
+use rspotify::clients::OAuthClient;
+use rspotify::http::BaseHttpClient;
+use rspotify::http::Form;
+use rspotify::http::Headers;
+use rspotify::http::HttpError;
+use rspotify::Token;
+use serde::Deserialize;
+
+pub const DEFAULT_ACCOUNTS_BASE_URL: &str = "https://accounts.spotify.com/";
+
+/// The accounts service turned down a token request (as opposed to it never
+/// getting there, or falling over).
+#[derive(Debug, Deserialize)]
+struct Rejected {
+    #[serde(skip)]
+    status: u16,
+    error: String,
+    error_description: Option<String>,
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error_description {
+            Some(description) => write!(f, "{}: {} ({})", self.status, self.error, description),
+            None => write!(f, "{}: {}", self.status, self.error),
+        }
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Point an authorization URL generated by rspotify at `accounts_url`.
+pub fn authorize_url(accounts_url: &str, url: &str) -> String {
+    match url.strip_prefix(DEFAULT_ACCOUNTS_BASE_URL) {
+        Some(path) => format!("{}{}", accounts_url, path),
+        None => url.into(),
+    }
+}
+
+/// Exchange an authorization code (as returned by
+/// [`login::get_code`](super::login::get_code)) for a token.
+///
+/// `verifier` is the PKCE code verifier (if using PKCE).
+pub async fn request(
+    client: &impl OAuthClient,
+    accounts_url: &str,
+    code: &str,
+    verifier: Option<&str>,
+) -> anyhow::Result<Token> {
+    let mut form = Form::new();
+    form.insert("grant_type", "authorization_code");
+    form.insert("code", code);
+    form.insert("redirect_uri", &client.get_oauth().redirect_uri);
+    if let Some(verifier) = verifier {
+        form.insert("code_verifier", verifier);
+    }
+    let headers = identify(client, &mut form);
+
+    fetch(client, accounts_url, &form, headers.as_ref()).await
+}
+
+/// Use `token`'s refresh token to get a new token (keeping whichever refresh
+/// token spotify hands back).
+///
+/// Fails with [`LoginRequired`] if the session can't be refreshed (i.e: there's
+/// no refresh token, or spotify has revoked it), and with some other error if
+/// spotify just couldn't be reached.
+pub async fn refresh(
+    client: &impl BaseClient,
+    accounts_url: &str,
+    token: &Token,
+) -> anyhow::Result<Token> {
+    let refresh_token = match &token.refresh_token {
+        Some(refresh_token) => refresh_token,
+        None => return Err(LoginRequired("spotify session can't be refreshed").into()),
+    };
+
+    let mut form = Form::new();
+    form.insert("grant_type", "refresh_token");
+    form.insert("refresh_token", refresh_token);
+    let headers = identify(client, &mut form);
+
+    let mut new = match fetch(client, accounts_url, &form, headers.as_ref()).await {
+        Ok(new) => new,
+        Err(e) if is_invalid_grant(&e) => {
+            log::debug!("spotify rejected the refresh token: {:#}", e);
+            return Err(LoginRequired("spotify session has expired").into());
+        }
+        Err(e) => return Err(e.context("could not refresh spotify session")),
+    };
+    // spotify doesn't always hand out a new refresh token (or repeat the
+    // scopes), in which case the old ones still stand
+    if new.refresh_token.is_none() {
+        new.refresh_token = Some(refresh_token.clone());
+    }
+    if new.scopes.is_empty() {
+        new.scopes = token.scopes.clone();
+    }
+    Ok(new)
+}
+
+/// Whether spotify rejected the grant (e.g: the refresh token was revoked).
+fn is_invalid_grant(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<Rejected>(),
+        Some(rejected) if rejected.status == 400 && rejected.error == "invalid_grant"
+    )
+}
+
+/// Identify the app making a token request, returning the headers to send.
+///
+/// Apps with a client secret authenticate using it, and those without (i.e:
+/// PKCE) identify themselves by ID.
+fn identify<'a>(client: &'a impl BaseClient, form: &mut Form<'a>) -> Option<Headers> {
+    let creds = client.get_creds();
+    let headers = creds.auth_headers();
+    if headers.is_none() {
+        form.insert("client_id", &creds.id);
+    }
+    headers
+}
+
+async fn fetch(
+    client: &impl BaseClient,
+    accounts_url: &str,
+    form: &Form<'_>,
+    headers: Option<&Headers>,
+) -> anyhow::Result<Token> {
+    let url = format!("{}api/token", accounts_url);
+    let resp = match client.get_http().post_form(&url, headers, form).await {
+        Ok(resp) => resp,
+        Err(HttpError::StatusCode(resp)) => {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            // only client errors come with an explanation
+            return Err(match serde_json::from_str::<Rejected>(&body) {
+                Ok(rejected) if status.is_client_error() => anyhow::Error::new(Rejected {
+                    status: status.as_u16(),
+                    ..rejected
+                }),
+                _ => anyhow::anyhow!("status code {}", status),
+            });
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut token = serde_json::from_str::<Token>(&resp)?;
+    token.expires_at = Utc::now().checked_add_signed(token.expires_in);
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(status: u16, error: &str) -> anyhow::Error {
+        anyhow::Error::new(Rejected {
+            status,
+            error: error.into(),
+            error_description: None,
+        })
+    }
+
+    #[test]
+    fn authorize_url_follows_accounts_url() {
+        let url = "https://accounts.spotify.com/authorize?client_id=abc&state=xyz";
+        assert_eq!(authorize_url(DEFAULT_ACCOUNTS_BASE_URL, url), url);
+        assert_eq!(
+            authorize_url("http://127.0.0.1:1234/", url),
+            "http://127.0.0.1:1234/authorize?client_id=abc&state=xyz"
+        );
+    }
+
+    #[test]
+    fn only_invalid_grants_require_login() {
+        assert!(is_invalid_grant(&rejected(400, "invalid_grant")));
+        assert!(is_invalid_grant(
+            &rejected(400, "invalid_grant").context("some context")
+        ));
+
+        assert!(!is_invalid_grant(&rejected(400, "invalid_request")));
+        assert!(!is_invalid_grant(&rejected(401, "invalid_client")));
+        assert!(!is_invalid_grant(&rejected(429, "invalid_grant")));
+        assert!(!is_invalid_grant(&anyhow::anyhow!("status code 503")));
+    }
+}
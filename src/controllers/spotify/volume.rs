@@ -0,0 +1,155 @@
+//! Handing the volume off from one spotify device to another.
+//!
+//! The spotify backend takes a little while to catch up after playback is
+//! transferred, so volume changes made right after a transfer may fail, or
+//! (worse) silently not stick. Instead of trusting the first request, the
+//! volume is set, read back, and re-set until it takes.
+
+use std::time::Duration;
+
+/// How many times to try setting the volume before giving up.
+const MAX_ATTEMPTS: u32 = 6;
+
+/// How long to wait before checking whether the volume took effect. Doubles
+/// after each failed attempt, up to [`MAX_RETRY_DELAY`].
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// The bits of the spotify API required to hand off volume.
+pub trait DeviceVolumeApi {
+    /// Returns `None` if the device isn't online.
+    async fn device_volume(&self, device_id: &str) -> anyhow::Result<Option<u8>>;
+    async fn set_device_volume(&self, device_id: &str, volume_percent: u8) -> anyhow::Result<()>;
+}
+
+/// Set the volume of the device with the given ID, retrying (with backoff)
+/// until spotify reports that it took effect.
+///
+/// Returns the number of attempts it took.
+pub async fn set_volume(
+    api: &impl DeviceVolumeApi,
+    device_id: &str,
+    volume_percent: u8,
+) -> anyhow::Result<u32> {
+    let mut delay = MIN_RETRY_DELAY;
+    let mut last_seen = None;
+    for attempt in 1..=MAX_ATTEMPTS {
+        match api.set_device_volume(device_id, volume_percent).await {
+            Ok(()) => {
+                tokio::time::sleep(delay).await;
+                match api.device_volume(device_id).await {
+                    Ok(Some(volume)) if volume == volume_percent => return Ok(attempt),
+                    Ok(Some(volume)) => last_seen = Some(volume),
+                    Ok(None) => log::debug!("target device disappeared (attempt {})", attempt),
+                    Err(e) => log::debug!("could not check volume (attempt {}): {:#}", attempt, e),
+                }
+            }
+            Err(e) => {
+                log::debug!("could not set volume (attempt {}): {:#}", attempt, e);
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+
+    Err(match last_seen {
+        Some(volume) => anyhow::anyhow!(
+            "volume didn't take effect after {} attempts (wanted {}%, still at {}%)",
+            MAX_ATTEMPTS,
+            volume_percent,
+            volume
+        ),
+        None => anyhow::anyhow!(
+            "could not confirm volume change after {} attempts",
+            MAX_ATTEMPTS
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Stands in for the spotify API, ignoring the first `ignore_sets` volume
+    /// changes (like the real thing does mid-transfer), and failing the first
+    /// `fail_sets` requests outright.
+    struct MockApi {
+        volume: RefCell<u8>,
+        ignore_sets: RefCell<u32>,
+        fail_sets: RefCell<u32>,
+        sets: RefCell<Vec<(String, u8)>>,
+    }
+
+    impl MockApi {
+        fn new(volume: u8) -> MockApi {
+            MockApi {
+                volume: RefCell::new(volume),
+                ignore_sets: RefCell::new(0),
+                fail_sets: RefCell::new(0),
+                sets: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl DeviceVolumeApi for MockApi {
+        async fn device_volume(&self, device_id: &str) -> anyhow::Result<Option<u8>> {
+            Ok((device_id == "target").then(|| *self.volume.borrow()))
+        }
+
+        async fn set_device_volume(
+            &self,
+            device_id: &str,
+            volume_percent: u8,
+        ) -> anyhow::Result<()> {
+            self.sets
+                .borrow_mut()
+                .push((device_id.to_string(), volume_percent));
+
+            if *self.fail_sets.borrow() > 0 {
+                *self.fail_sets.borrow_mut() -= 1;
+                return Err(anyhow::anyhow!("device not found"));
+            }
+            if *self.ignore_sets.borrow() > 0 {
+                *self.ignore_sets.borrow_mut() -= 1;
+                return Ok(());
+            }
+            *self.volume.borrow_mut() = volume_percent;
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sets_volume_by_device_id() {
+        let api = MockApi::new(100);
+        assert_eq!(set_volume(&api, "target", 42).await.unwrap(), 1);
+        assert_eq!(*api.volume.borrow(), 42);
+        assert_eq!(*api.sets.borrow(), vec![("target".to_string(), 42)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_until_volume_takes() {
+        let api = MockApi::new(100);
+        *api.fail_sets.borrow_mut() = 1;
+        *api.ignore_sets.borrow_mut() = 2;
+
+        assert_eq!(set_volume(&api, "target", 42).await.unwrap(), 4);
+        assert_eq!(*api.volume.borrow(), 42);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_failure() {
+        let api = MockApi::new(100);
+        *api.ignore_sets.borrow_mut() = u32::MAX;
+
+        let err = set_volume(&api, "target", 42).await.unwrap_err();
+        assert!(err.to_string().contains("still at 100%"), "{}", err);
+        assert_eq!(api.sets.borrow().len(), MAX_ATTEMPTS as usize);
+
+        // device is offline
+        let api = MockApi::new(100);
+        assert!(set_volume(&api, "other", 42).await.is_err());
+    }
+}
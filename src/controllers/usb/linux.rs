@@ -0,0 +1,227 @@
+//! Listens for the kernel's uevents (i.e: the same netlink broadcasts `udev`
+//! itself listens to), which doesn't require `libudev` to be installed.
+
+use super::OnEvent;
+use super::UsbEvent;
+use super::UsbId;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Multicast group the kernel broadcasts uevents on.
+const KERNEL_UEVENT_GROUP: u32 = 1;
+
+/// How often the listener thread checks if it should stop.
+const RECV_TIMEOUT_MS: libc::suseconds_t = 500_000;
+
+pub struct Watch {
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+pub fn watch(id: UsbId, on_event: OnEvent) -> anyhow::Result<Watch> {
+    let socket = open_uevent_socket()?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    std::thread::spawn({
+        let stop = stop.clone();
+        move || {
+            let mut buf = vec![0; 8192];
+            while !stop.load(Ordering::Relaxed) {
+                let len = unsafe {
+                    libc::recv(
+                        socket.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if len < 0 {
+                    let e = io::Error::last_os_error();
+                    match e.kind() {
+                        io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted => continue,
+                        _ => {
+                            log::error!("stopped watching for USB devices: {}", e);
+                            return;
+                        }
+                    }
+                }
+
+                if let Some(event) = parse_uevent(&buf[..len as usize], id) {
+                    on_event(event)
+                }
+            }
+        }
+    });
+
+    Ok(Watch { stop })
+}
+
+fn open_uevent_socket() -> io::Result<OwnedFd> {
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_KOBJECT_UEVENT,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = OwnedFd::from_raw_fd(fd);
+
+        let mut addr: libc::sockaddr_nl = std::mem::zeroed();
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = KERNEL_UEVENT_GROUP;
+        if libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        // so that the listener thread periodically gets a chance to notice it
+        // should stop
+        let timeout = libc::timeval {
+            tv_sec: 0,
+            tv_usec: RECV_TIMEOUT_MS,
+        };
+        if libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+}
+
+/// Parse a uevent, returning an event if it's for the device we care about.
+///
+/// uevents are a header (`<action>@<devpath>`), followed by a bunch of
+/// `KEY=value` pairs, all NUL-separated. USB devices are identified by their
+/// `PRODUCT` (`<vid>/<pid>/<bcdDevice>`, in unpadded hex).
+fn parse_uevent(msg: &[u8], id: UsbId) -> Option<UsbEvent> {
+    let (mut action, mut subsystem, mut devtype, mut product) = (None, None, None, None);
+    for field in msg.split(|b| *b == 0).skip(1) {
+        // none of the fields we care about are ever anything but ASCII
+        let field = match std::str::from_utf8(field) {
+            Ok(field) => field,
+            Err(_) => continue,
+        };
+        match field.split_once('=') {
+            Some(("ACTION", v)) => action = Some(v),
+            Some(("SUBSYSTEM", v)) => subsystem = Some(v),
+            Some(("DEVTYPE", v)) => devtype = Some(v),
+            Some(("PRODUCT", v)) => product = Some(v),
+            _ => {}
+        }
+    }
+
+    // each interface of a device gets its own uevent too
+    if subsystem != Some("usb") || devtype != Some("usb_device") {
+        return None;
+    }
+
+    let mut product = product?.split('/');
+    let vendor = u16::from_str_radix(product.next()?, 16).ok()?;
+    let product = u16::from_str_radix(product.next()?, 16).ok()?;
+    if (UsbId { vendor, product }) != id {
+        return None;
+    }
+
+    match action? {
+        "add" => Some(UsbEvent::Connected),
+        "remove" => Some(UsbEvent::Disconnected),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: UsbId = UsbId {
+        vendor: 0x1532,
+        product: 0x005c,
+    };
+
+    // captured via `udevadm monitor --kernel --property`
+    const DEVICE_ADD: &[u8] = b"add@/devices/pci0000:00/0000:00:14.0/usb1/1-2\0\
+        ACTION=add\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2\0SUBSYSTEM=usb\0\
+        MAJOR=189\0MINOR=3\0DEVNAME=bus/usb/001/004\0DEVTYPE=usb_device\0\
+        PRODUCT=1532/5c/200\0TYPE=0/0/0\0BUSNUM=001\0DEVNUM=004\0SEQNUM=4242\0";
+
+    const DEVICE_REMOVE: &[u8] = b"remove@/devices/pci0000:00/0000:00:14.0/usb1/1-2\0\
+        ACTION=remove\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2\0SUBSYSTEM=usb\0\
+        MAJOR=189\0MINOR=3\0DEVNAME=bus/usb/001/004\0DEVTYPE=usb_device\0\
+        PRODUCT=1532/5c/200\0TYPE=0/0/0\0BUSNUM=001\0DEVNUM=004\0SEQNUM=4250\0";
+
+    const INTERFACE_ADD: &[u8] = b"add@/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0\0\
+        ACTION=add\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0\0\
+        SUBSYSTEM=usb\0DEVTYPE=usb_interface\0PRODUCT=1532/5c/200\0TYPE=0/0/0\0\
+        INTERFACE=3/1/2\0MODALIAS=usb:v1532p005Cd0200dc00dsc00dp00ic03isc01ip02in00\0\
+        SEQNUM=4243\0";
+
+    #[test]
+    fn device_add_remove() {
+        assert_eq!(parse_uevent(DEVICE_ADD, ID), Some(UsbEvent::Connected));
+        assert_eq!(
+            parse_uevent(DEVICE_REMOVE, ID),
+            Some(UsbEvent::Disconnected)
+        );
+
+        // e.g: driver binds
+        let bind = String::from_utf8_lossy(DEVICE_ADD).replace("ACTION=add", "ACTION=bind");
+        assert_eq!(parse_uevent(bind.as_bytes(), ID), None);
+    }
+
+    #[test]
+    fn ignores_interfaces() {
+        assert_eq!(parse_uevent(INTERFACE_ADD, ID), None);
+    }
+
+    #[test]
+    fn ignores_other_devices() {
+        let other = UsbId {
+            vendor: 0x1532,
+            product: 0x005d,
+        };
+        assert_eq!(parse_uevent(DEVICE_ADD, other), None);
+    }
+
+    #[test]
+    fn malformed_events() {
+        for product in ["PRODUCT=", "PRODUCT=1532", "PRODUCT=zzzz/5c/200"] {
+            let msg = String::from_utf8_lossy(DEVICE_ADD).replace("PRODUCT=1532/5c/200", product);
+            assert_eq!(parse_uevent(msg.as_bytes(), ID), None, "{}", product);
+        }
+
+        let no_product = String::from_utf8_lossy(DEVICE_ADD).replace("PRODUCT=", "NOPE=");
+        assert_eq!(parse_uevent(no_product.as_bytes(), ID), None);
+
+        // a non-UTF-8 field only spoils itself
+        let mut msg = DEVICE_ADD.to_vec();
+        msg.extend_from_slice(b"ID_MODEL=\xff\xfe\0");
+        assert_eq!(parse_uevent(&msg, ID), Some(UsbEvent::Connected));
+
+        assert_eq!(parse_uevent(b"", ID), None);
+    }
+}
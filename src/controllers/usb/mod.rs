@@ -0,0 +1,139 @@
+//! Watch for a USB device being connected / disconnected.
+
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use tokio::sync::mpsc;
+
+cfg_if::cfg_if! {
+    if #[cfg(windows)] {
+        #[path = "windows.rs"]
+        mod sys;
+    } else if #[cfg(target_os = "linux")] {
+        #[path = "linux.rs"]
+        mod sys;
+    } else {
+        mod sys {
+            pub struct Watch;
+
+            pub fn watch(_id: super::UsbId, _on_event: super::OnEvent) -> anyhow::Result<Watch> {
+                Err(anyhow::anyhow!(
+                    "watching for USB devices is not supported on this platform"
+                ))
+            }
+        }
+    }
+}
+
+/// Callback invoked whenever the watched device is connected / disconnected.
+type OnEvent = Box<dyn Fn(UsbEvent) + Send + Sync>;
+
+/// A USB device's vendor and product IDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbId {
+    pub vendor: u16,
+    pub product: u16,
+}
+
+impl std::str::FromStr for UsbId {
+    type Err = anyhow::Error;
+
+    /// Parse a `VID:PID` pair of hex IDs (e.g: `1532:005C`), as displayed by
+    /// `lsusb` / Device Manager.
+    fn from_str(s: &str) -> anyhow::Result<UsbId> {
+        let (vendor, product) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("expected a USB device ID in VID:PID format"))?;
+        let parse = |id| {
+            u16::from_str_radix(id, 16)
+                .map_err(|_| anyhow::anyhow!("invalid USB device ID: {:?}", s))
+        };
+        Ok(UsbId {
+            vendor: parse(vendor)?,
+            product: parse(product)?,
+        })
+    }
+}
+
+impl std::fmt::Display for UsbId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04X}:{:04X}", self.vendor, self.product)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbEvent {
+    Connected,
+    Disconnected,
+}
+
+/// Async stream of connect / disconnect events for a single USB device,
+/// returned by [`watch`].
+pub struct UsbEvents {
+    events: mpsc::UnboundedReceiver<UsbEvent>,
+    _watch: sys::Watch,
+}
+
+impl tokio_stream::Stream for UsbEvents {
+    type Item = UsbEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<UsbEvent>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+/// Watch for the USB device with the given ID being connected / disconnected,
+/// until the returned stream is dropped.
+///
+/// Only transitions are reported (i.e: the stream never yields the same event
+/// twice in a row).
+pub fn watch(id: UsbId) -> anyhow::Result<UsbEvents> {
+    let (tx, events) = mpsc::unbounded_channel();
+    let last = Mutex::new(None);
+    let watch = sys::watch(
+        id,
+        Box::new(move |event| {
+            let mut last = last.lock().unwrap();
+            if *last != Some(event) {
+                *last = Some(event);
+                let _ = tx.send(event);
+            }
+        }),
+    )?;
+
+    Ok(UsbEvents {
+        events,
+        _watch: watch,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_usb_id() {
+        let id = UsbId {
+            vendor: 0x1532,
+            product: 0x005c,
+        };
+        assert_eq!("1532:005C".parse::<UsbId>().unwrap(), id);
+        assert_eq!("1532:5c".parse::<UsbId>().unwrap(), id);
+        assert_eq!(id.to_string(), "1532:005C");
+
+        for bad in [
+            "",
+            "1532",
+            "1532:",
+            ":005C",
+            "1532-005C",
+            "1532:005C:01",
+            "1532:zzzz",
+        ] {
+            assert!(bad.parse::<UsbId>().is_err(), "{:?}", bad);
+        }
+        // doesn't fit in a u16
+        assert!("12345:005C".parse::<UsbId>().is_err());
+    }
+}
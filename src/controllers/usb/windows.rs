@@ -0,0 +1,130 @@
+use super::OnEvent;
+use super::UsbEvent;
+use super::UsbId;
+use std::ffi::c_void;
+use windows::Win32::Devices::DeviceAndDriverInstallation::*;
+use windows::Win32::Devices::Usb::GUID_DEVINTERFACE_USB_DEVICE;
+
+struct Context {
+    id: UsbId,
+    on_event: OnEvent,
+}
+
+pub struct Watch {
+    handle: HCMNOTIFICATION,
+    // must outlive the registration, since the callback holds a pointer to it
+    _context: Box<Context>,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        // blocks until any in-flight callbacks have returned
+        unsafe {
+            CM_Unregister_Notification(self.handle);
+        }
+    }
+}
+
+pub fn watch(id: UsbId, on_event: OnEvent) -> anyhow::Result<Watch> {
+    let context = Box::new(Context { id, on_event });
+
+    let mut filter: CM_NOTIFY_FILTER = unsafe { std::mem::zeroed() };
+    filter.cbSize = std::mem::size_of::<CM_NOTIFY_FILTER>() as u32;
+    filter.FilterType = CM_NOTIFY_FILTER_TYPE_DEVICEINTERFACE;
+    filter.u.DeviceInterface.ClassGuid = GUID_DEVINTERFACE_USB_DEVICE;
+
+    let mut handle = 0;
+    let res = unsafe {
+        CM_Register_Notification(
+            &filter,
+            &*context as *const Context as *const c_void,
+            Some(on_notify),
+            &mut handle,
+        )
+    };
+    if res != CR_SUCCESS {
+        return Err(anyhow::anyhow!(
+            "could not register for USB device notifications (CONFIGRET {})",
+            res.0
+        ));
+    }
+
+    Ok(Watch {
+        handle: HCMNOTIFICATION(handle),
+        _context: context,
+    })
+}
+
+unsafe extern "system" fn on_notify(
+    _handle: HCMNOTIFICATION,
+    context: *const c_void,
+    action: CM_NOTIFY_ACTION,
+    data: *const CM_NOTIFY_EVENT_DATA,
+    _size: u32,
+) -> u32 {
+    let context = &*(context as *const Context);
+
+    let event = match action {
+        CM_NOTIFY_ACTION_DEVICEINTERFACEARRIVAL => UsbEvent::Connected,
+        CM_NOTIFY_ACTION_DEVICEINTERFACEREMOVAL => UsbEvent::Disconnected,
+        _ => return 0,
+    };
+
+    // the symbolic link is a NUL-terminated string which runs off the end of the
+    // struct
+    let link = std::ptr::addr_of!((*data).u.DeviceInterface.SymbolicLink) as *const u16;
+    let len = (0..).take_while(|&i| *link.add(i) != 0).count();
+    let link = String::from_utf16_lossy(std::slice::from_raw_parts(link, len));
+
+    if parse_symbolic_link(&link) == Some(context.id) {
+        (context.on_event)(event);
+    }
+
+    0
+}
+
+/// Parse the device's ID out of its symbolic link (e.g:
+/// `\\?\USB#VID_1532&PID_005C#...`).
+fn parse_symbolic_link(link: &str) -> Option<UsbId> {
+    let link = link.to_ascii_uppercase();
+    let id = |prefix: &str| {
+        let start = link.find(prefix)? + prefix.len();
+        u16::from_str_radix(link.get(start..start + 4)?, 16).ok()
+    };
+    Some(UsbId {
+        vendor: id("VID_")?,
+        product: id("PID_")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbolic_links() {
+        let id = UsbId {
+            vendor: 0x1532,
+            product: 0x005c,
+        };
+        assert_eq!(
+            parse_symbolic_link(
+                r"\\?\USB#VID_1532&PID_005C#5&2a0c2a1d&0&2#{a5dcbf10-6530-11d2-901f-00c04fb951ed}"
+            ),
+            Some(id)
+        );
+        // case-insensitive
+        assert_eq!(
+            parse_symbolic_link(r"\\?\usb#vid_1532&pid_005c#5&2a0c2a1d&0&2"),
+            Some(id)
+        );
+
+        assert_eq!(
+            parse_symbolic_link(r"\\?\USB#VID_1532#5&2a0c2a1d&0&2"),
+            None
+        );
+        assert_eq!(parse_symbolic_link(r"\\?\USB#VID_15&PID_005C"), None);
+        assert_eq!(parse_symbolic_link(r"\\?\USB#VID_ZZZZ&PID_005C"), None);
+        assert_eq!(parse_symbolic_link(""), None);
+    }
+}
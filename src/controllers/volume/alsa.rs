@@ -0,0 +1,379 @@
+use super::AudioEndpoint;
+use super::VolumeBackend;
+use anyhow::anyhow;
+use anyhow::Context;
+use std::process::Command;
+
+/// Controls tried (in order) when no mixer control was explicitly configured.
+const DEFAULT_CONTROLS: &[&str] = &["Master", "PCM"];
+
+/// Talks directly to an ALSA mixer control by shelling out to `amixer`.
+///
+/// Only really useful on headless boxes that aren't running a sound server, as
+/// PulseAudio / PipeWire tend to fight over the hardware mixer.
+pub struct AlsaVolume {
+    card: Option<String>,
+    control: String,
+}
+
+impl AlsaVolume {
+    /// `card` can be either the card's index, its ID, or its name. If it's
+    /// `None`, the default card is used.
+    pub fn new(card: Option<&str>, control: Option<&str>) -> anyhow::Result<AlsaVolume> {
+        let card = match card {
+            None => None,
+            Some(card) => {
+                let known = list_cards().unwrap_or_default();
+                match known
+                    .into_iter()
+                    .find(|c| c.endpoint.matches(card) || c.alsa_id == card)
+                {
+                    Some(c) => Some(c.endpoint.id),
+                    // let `amixer` have a crack at it
+                    None => Some(card.to_string()),
+                }
+            }
+        };
+
+        let control = match control {
+            Some(control) => {
+                amixer(card.as_deref(), &["sget", control])
+                    .context(format!("could not find ALSA mixer control {:?}", control))?;
+                control.to_string()
+            }
+            None => DEFAULT_CONTROLS
+                .iter()
+                .find(|control| amixer(card.as_deref(), &["sget", control]).is_ok())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "could not find any of the default ALSA mixer controls ({})",
+                        DEFAULT_CONTROLS.join(", ")
+                    )
+                })?
+                .to_string(),
+        };
+
+        log::debug!(
+            "using ALSA mixer control {:?} on card {:?}",
+            control,
+            card.as_deref().unwrap_or("default")
+        );
+
+        Ok(AlsaVolume { card, control })
+    }
+
+    fn read_state(&self) -> anyhow::Result<MixerState> {
+        let out = amixer(self.card.as_deref(), &["sget", &self.control])?;
+        parse_mixer_state(&out)
+    }
+}
+
+impl VolumeBackend for AlsaVolume {
+    fn get_master_volume(&self) -> anyhow::Result<f32> {
+        let state = self.read_state()?;
+        Ok((state.raw - state.min) as f32 / (state.max - state.min) as f32)
+    }
+
+    fn set_master_volume(&self, vol: f32) -> anyhow::Result<()> {
+        let state = self.read_state()?;
+        let raw = state.min + (vol.clamp(0.0, 1.0) * (state.max - state.min) as f32).round() as i64;
+        amixer(
+            self.card.as_deref(),
+            &["sset", &self.control, &raw.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn get_mute(&self) -> anyhow::Result<bool> {
+        // controls without a playback switch can't be muted
+        Ok(self.read_state()?.muted.unwrap_or(false))
+    }
+
+    fn set_mute(&self, muted: bool) -> anyhow::Result<()> {
+        if self.read_state()?.muted.is_none() {
+            return Err(anyhow!(
+                "ALSA mixer control {:?} cannot be muted",
+                self.control
+            ));
+        }
+
+        amixer(
+            self.card.as_deref(),
+            &["sset", &self.control, if muted { "mute" } else { "unmute" }],
+        )?;
+        Ok(())
+    }
+
+    fn list_endpoints(&self) -> anyhow::Result<Vec<AudioEndpoint>> {
+        Ok(list_cards()?
+            .into_iter()
+            .map(|c| {
+                let mut endpoint = c.endpoint;
+                endpoint.is_default = match &self.card {
+                    Some(card) => endpoint.id == *card,
+                    None => endpoint.id == "0",
+                };
+                endpoint
+            })
+            .collect())
+    }
+}
+
+struct Card {
+    /// uses the card index as the endpoint ID
+    endpoint: AudioEndpoint,
+    /// e.g: `PCH`
+    alsa_id: String,
+}
+
+fn list_cards() -> anyhow::Result<Vec<Card>> {
+    let cards = std::fs::read_to_string("/proc/asound/cards")
+        .context("could not read list of ALSA cards")?;
+    Ok(parse_cards(&cards))
+}
+
+/// Parse `/proc/asound/cards`, which looks something like:
+///
+/// ```text
+///  0 [PCH            ]: HDA-Intel - HDA Intel PCH
+///                       HDA Intel PCH at 0xf7f10000 irq 32
+///  1 [Device         ]: USB-Audio - USB Audio Device
+///                       Generic USB Audio Device at usb-0000:00:14.0-2, full speed
+/// ```
+fn parse_cards(s: &str) -> Vec<Card> {
+    s.lines()
+        .filter_map(|l| {
+            let (index, rest) = l.trim().split_once(" [")?;
+            let index = index.parse::<u32>().ok()?;
+            let (alsa_id, rest) = rest.split_once(']')?;
+            let (_driver, name) = rest.split_once(" - ")?;
+            Some(Card {
+                endpoint: AudioEndpoint {
+                    id: index.to_string(),
+                    name: name.trim().to_string(),
+                    is_default: false,
+                },
+                alsa_id: alsa_id.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+fn amixer(card: Option<&str>, args: &[&str]) -> anyhow::Result<String> {
+    let mut cmd = Command::new("amixer");
+    if let Some(card) = card {
+        cmd.args(["-c", card]);
+    }
+
+    let output = cmd
+        .args(args)
+        .env("LC_ALL", "C")
+        .output()
+        .context("failed to run `amixer` (is alsa-utils installed?)")?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`amixer {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[derive(Debug)]
+struct MixerState {
+    min: i64,
+    max: i64,
+    /// raw volume of the loudest channel
+    raw: i64,
+    /// `None` if the control doesn't have a playback switch
+    muted: Option<bool>,
+}
+
+/// Parse the output of `amixer sget <control>`, which looks something like:
+///
+/// ```text
+/// Simple mixer control 'Master',0
+///   Capabilities: pvolume pswitch pswitch-joined
+///   Playback channels: Front Left - Front Right
+///   Limits: Playback 0 - 87
+///   Mono:
+///   Front Left: Playback 60 [69%] [-20.25dB] [on]
+///   Front Right: Playback 60 [69%] [-20.25dB] [on]
+/// ```
+fn parse_mixer_state(s: &str) -> anyhow::Result<MixerState> {
+    let (min, max) = s
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("Limits: Playback "))
+        .and_then(|limits| {
+            let (min, max) = limits.split_once(" - ")?;
+            Some((min.trim().parse().ok()?, max.trim().parse().ok()?))
+        })
+        .ok_or_else(|| anyhow!("mixer control has no playback volume"))?;
+
+    if max <= min {
+        return Err(anyhow!("mixer control has an empty volume range"));
+    }
+
+    let channels = s
+        .lines()
+        .filter_map(|l| l.split_once(": Playback "))
+        .filter(|(chan, _)| chan.trim() != "Limits")
+        .map(|(_chan, rest)| rest)
+        .collect::<Vec<_>>();
+
+    let raw = channels
+        .iter()
+        .filter_map(|rest| rest.split_whitespace().next()?.parse::<i64>().ok())
+        .max()
+        .ok_or_else(|| anyhow!("unexpected `amixer` output: {:?}", s))?;
+
+    // the output is only considered muted if _every_ channel is switched off
+    let switches = channels
+        .iter()
+        .filter_map(|rest| {
+            rest.split_whitespace().find_map(|field| match field {
+                "[on]" => Some(true),
+                "[off]" => Some(false),
+                _ => None,
+            })
+        })
+        .collect::<Vec<_>>();
+    let muted = if switches.is_empty() {
+        None
+    } else {
+        Some(switches.iter().all(|on| !on))
+    };
+
+    Ok(MixerState {
+        min,
+        max,
+        raw,
+        muted,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // captured via `cat /proc/asound/cards`
+    const CARDS: &str = "\
+ 0 [PCH            ]: HDA-Intel - HDA Intel PCH
+                      HDA Intel PCH at 0xf7f10000 irq 32
+ 1 [NVidia         ]: HDA-Intel - HDA NVidia
+                      HDA NVidia at 0xf7080000 irq 17
+ 2 [Device         ]: USB-Audio - USB Audio Device
+                      Generic USB Audio Device at usb-0000:00:14.0-2, full speed
+10 [Modi3          ]: USB-Audio - Schiit Modi 3
+                      Schiit Audio Schiit Modi 3 at usb-0000:00:14.0-4, high speed
+";
+
+    // captured via `LC_ALL=C amixer sget Master`
+    const MASTER: &str = "\
+Simple mixer control 'Master',0
+  Capabilities: pvolume pvolume-joined pswitch pswitch-joined
+  Playback channels: Mono
+  Limits: Playback 0 - 87
+  Mono: Playback 60 [69%] [-20.25dB] [on]
+";
+
+    // captured via `LC_ALL=C amixer sget PCM`
+    const PCM: &str = "\
+Simple mixer control 'PCM',0
+  Capabilities: pvolume pswitch
+  Playback channels: Front Left - Front Right
+  Limits: Playback 0 - 255
+  Mono:
+  Front Left: Playback 191 [75%] [-12.75dB] [off]
+  Front Right: Playback 204 [80%] [-10.20dB] [on]
+";
+
+    // captured via `LC_ALL=C amixer -c 2 sget Speaker` (no mute switch)
+    const SPEAKER: &str = "\
+Simple mixer control 'Speaker',0
+  Capabilities: pvolume
+  Playback channels: Front Left - Front Right
+  Limits: Playback 0 - 37
+  Mono:
+  Front Left: Playback 30 [81%] [-7.00dB]
+  Front Right: Playback 30 [81%] [-7.00dB]
+";
+
+    #[test]
+    fn cards() {
+        let cards = parse_cards(CARDS);
+        let cards: Vec<_> = cards
+            .iter()
+            .map(|c| {
+                (
+                    c.endpoint.id.as_str(),
+                    c.alsa_id.as_str(),
+                    c.endpoint.name.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            cards,
+            [
+                ("0", "PCH", "HDA Intel PCH"),
+                ("1", "NVidia", "HDA NVidia"),
+                ("2", "Device", "USB Audio Device"),
+                ("10", "Modi3", "Schiit Modi 3"),
+            ]
+        );
+
+        assert!(parse_cards("--- no soundcards ---\n").is_empty());
+    }
+
+    #[test]
+    fn master() {
+        let state = parse_mixer_state(MASTER).unwrap();
+        assert_eq!((state.min, state.max, state.raw), (0, 87, 60));
+        assert_eq!(state.muted, Some(false));
+
+        let muted = parse_mixer_state(&MASTER.replace("[on]", "[off]")).unwrap();
+        assert_eq!(muted.muted, Some(true));
+    }
+
+    #[test]
+    fn pcm() {
+        // loudest channel wins, and it's only muted if every channel is
+        let state = parse_mixer_state(PCM).unwrap();
+        assert_eq!((state.min, state.max, state.raw), (0, 255, 204));
+        assert_eq!(state.muted, Some(false));
+
+        let muted = parse_mixer_state(&PCM.replace("[on]", "[off]")).unwrap();
+        assert_eq!(muted.muted, Some(true));
+    }
+
+    #[test]
+    fn no_switch() {
+        let state = parse_mixer_state(SPEAKER).unwrap();
+        assert_eq!((state.min, state.max, state.raw), (0, 37, 30));
+        assert_eq!(state.muted, None);
+    }
+
+    #[test]
+    fn bad_controls() {
+        // capture-only
+        let capture = "\
+Simple mixer control 'Capture',0
+  Capabilities: cvolume cswitch
+  Capture channels: Front Left - Front Right
+  Limits: Capture 0 - 63
+  Front Left: Capture 39 [62%] [12.00dB] [on]
+  Front Right: Capture 39 [62%] [12.00dB] [on]
+";
+        let err = parse_mixer_state(capture).unwrap_err();
+        assert!(err.to_string().contains("no playback volume"), "{}", err);
+
+        let empty = MASTER.replace("Playback 0 - 87", "Playback 0 - 0");
+        let err = parse_mixer_state(&empty).unwrap_err();
+        assert!(err.to_string().contains("empty volume range"), "{}", err);
+
+        assert!(parse_mixer_state("Limits: Playback 0 - 87\n").is_err());
+    }
+}
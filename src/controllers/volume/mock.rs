@@ -0,0 +1,184 @@
+use super::AudioEndpoint;
+use super::OnChange;
+use super::VolumeBackend;
+use super::VolumeState;
+use super::Watch;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// A call made against a [`MockVolume`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockCall {
+    GetMasterVolume,
+    SetMasterVolume(f32),
+    GetMute,
+    SetMute(bool),
+    GetAppVolume(String),
+    SetAppVolume(String, f32),
+    SetDefaultEndpoint(String),
+}
+
+struct MockState {
+    volume: f32,
+    muted: bool,
+    apps: Vec<(String, f32)>,
+    calls: Vec<MockCall>,
+    watchers: Vec<(usize, OnChange)>,
+    next_watcher_id: usize,
+}
+
+impl std::fmt::Debug for MockState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockState")
+            .field("volume", &self.volume)
+            .field("muted", &self.muted)
+            .field("apps", &self.apps)
+            .field("calls", &self.calls)
+            .field("watchers", &self.watchers.len())
+            .finish()
+    }
+}
+
+/// In-memory [`VolumeBackend`] which records every call made against it.
+///
+/// Cloning a `MockVolume` returns a handle to the _same_ underlying state, so
+/// a clone can be kept around to inspect a mock that's been handed off to a
+/// [`VolumeController`](super::VolumeController).
+#[derive(Debug, Clone)]
+pub struct MockVolume {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockVolume {
+    pub fn new(volume: f32) -> MockVolume {
+        MockVolume {
+            state: Arc::new(Mutex::new(MockState {
+                volume,
+                muted: false,
+                apps: Vec::new(),
+                calls: Vec::new(),
+                watchers: Vec::new(),
+                next_watcher_id: 0,
+            })),
+        }
+    }
+
+    /// Pretend that `app` is playing audio at the given volume.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn add_app(&self, app: &str, vol: f32) {
+        self.state.lock().unwrap().apps.push((app.to_string(), vol));
+    }
+
+    /// Return every call made against the mock (so far).
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    fn record(&self, call: MockCall) -> std::sync::MutexGuard<'_, MockState> {
+        log::info!("mock volume backend: {:?}", call);
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(call);
+        state
+    }
+
+    /// Notify any watchers of the current volume / mute state (much like the
+    /// OS would, after _any_ change).
+    fn notify(&self) {
+        let (watchers, current) = {
+            let state = self.state.lock().unwrap();
+            let current = VolumeState {
+                volume: state.volume,
+                muted: state.muted,
+            };
+            (state.watchers.clone(), current)
+        };
+
+        for (_, on_change) in watchers {
+            on_change(current)
+        }
+    }
+}
+
+/// Unregisters a watcher when dropped.
+struct MockWatch {
+    state: Arc<Mutex<MockState>>,
+    id: usize,
+}
+
+impl Drop for MockWatch {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.watchers.retain(|(id, _)| *id != self.id);
+    }
+}
+
+impl VolumeBackend for MockVolume {
+    fn get_master_volume(&self) -> anyhow::Result<f32> {
+        Ok(self.record(MockCall::GetMasterVolume).volume)
+    }
+
+    fn set_master_volume(&self, vol: f32) -> anyhow::Result<()> {
+        self.record(MockCall::SetMasterVolume(vol)).volume = vol;
+        self.notify();
+        Ok(())
+    }
+
+    fn get_mute(&self) -> anyhow::Result<bool> {
+        Ok(self.record(MockCall::GetMute).muted)
+    }
+
+    fn set_mute(&self, muted: bool) -> anyhow::Result<()> {
+        self.record(MockCall::SetMute(muted)).muted = muted;
+        self.notify();
+        Ok(())
+    }
+
+    fn get_app_volume(&self, app: &str) -> anyhow::Result<Option<f32>> {
+        let state = self.record(MockCall::GetAppVolume(app.to_string()));
+        Ok(state
+            .apps
+            .iter()
+            .find(|(name, _)| super::app_name_matches(name, app))
+            .map(|(_, vol)| *vol))
+    }
+
+    fn set_app_volume(&self, app: &str, vol: f32) -> anyhow::Result<bool> {
+        let mut state = self.record(MockCall::SetAppVolume(app.to_string(), vol));
+        let mut found = false;
+        for (_, app_vol) in state
+            .apps
+            .iter_mut()
+            .filter(|(name, _)| super::app_name_matches(name, app))
+        {
+            *app_vol = vol;
+            found = true;
+        }
+        Ok(found)
+    }
+
+    fn list_endpoints(&self) -> anyhow::Result<Vec<AudioEndpoint>> {
+        Ok(vec![AudioEndpoint {
+            id: "mock".into(),
+            name: "Mock Output".into(),
+            is_default: true,
+        }])
+    }
+
+    fn set_default_endpoint(&self, endpoint: &str) -> anyhow::Result<()> {
+        drop(self.record(MockCall::SetDefaultEndpoint(endpoint.to_string())));
+        Ok(())
+    }
+
+    fn watch(&self, on_change: OnChange) -> anyhow::Result<Watch> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_watcher_id;
+        state.next_watcher_id += 1;
+        state.watchers.push((id, on_change));
+
+        Ok(Watch::new(MockWatch {
+            state: self.state.clone(),
+            id,
+        }))
+    }
+}
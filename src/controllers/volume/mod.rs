@@ -1,46 +1,362 @@
-cfg_if::cfg_if! {
-    if #[cfg(windows)] {
-        #[path = "windows.rs"]
-        mod sys;
-    } else {
-        mod sys {
-            pub struct VolumeControllerImpl;
-            impl VolumeControllerImpl {
-                pub fn new_system_default() -> anyhow::Result<Self> {
-                    Err(anyhow::anyhow!("no volume controller is currently implemented for this platform. consider opening a PR?"))
-                }
+use crate::config::SystemAudio;
+use serde::Deserialize;
+use serde::Serialize;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::sync::mpsc;
 
-                pub fn get_master_volume(&self) -> anyhow::Result<f32> {
-                    Ok(0.0)
-                }
+#[cfg(target_os = "linux")]
+mod alsa;
+pub mod mock;
+#[cfg(target_os = "linux")]
+mod pulse;
+#[cfg(windows)]
+mod windows;
 
-                pub fn set_master_volume(&self, _vol: f32) -> anyhow::Result<()> {
-                    Ok(())
-                }
-            }
+/// An audio output device.
+#[derive(Debug, Clone)]
+pub struct AudioEndpoint {
+    /// Backend-specific unique ID
+    pub id: String,
+    /// Human-readable name
+    pub name: String,
+    /// Whether this is the system's default output
+    pub is_default: bool,
+}
+
+impl AudioEndpoint {
+    /// Check if `endpoint` (either an ID or a name) refers to this endpoint.
+    fn matches(&self, endpoint: &str) -> bool {
+        self.id == endpoint || self.name.eq_ignore_ascii_case(endpoint)
+    }
+}
+
+/// Snapshot of the master volume / mute state.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct VolumeState {
+    /// 0.0 = mute, 1.0 = max volume
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl VolumeState {
+    /// Check if two states are the same, give or take some rounding error
+    /// (e.g: PulseAudio stores volumes as integers).
+    pub fn approx_eq(&self, other: &VolumeState) -> bool {
+        (self.volume - other.volume).abs() < 0.005 && self.muted == other.muted
+    }
+}
+
+/// Callback invoked whenever the master volume / mute state changes.
+pub type OnChange = Arc<dyn Fn(VolumeState) + Send + Sync>;
+
+/// How often to check for changes when the backend can't notify us of them.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Keeps a change notification callback registered until dropped.
+pub struct Watch(#[allow(dead_code)] Box<dyn Send>);
+
+impl Watch {
+    fn new(guard: impl Send + 'static) -> Watch {
+        Watch(Box::new(guard))
+    }
+}
+
+/// A way of controlling system audio.
+pub trait VolumeBackend: Send + Sync {
+    /// Get the master volume (0.0 = mute, 1.0 = max volume)
+    fn get_master_volume(&self) -> anyhow::Result<f32>;
+
+    /// Set the master volume (0.0 = mute, 1.0 = max volume)
+    fn set_master_volume(&self, vol: f32) -> anyhow::Result<()>;
+
+    /// Check if the output is muted
+    fn get_mute(&self) -> anyhow::Result<bool>;
+
+    /// Mute / unmute the output
+    fn set_mute(&self, muted: bool) -> anyhow::Result<()>;
+
+    /// Get the volume of a specific application (0.0 = mute, 1.0 = max
+    /// volume), or `None` if the application isn't currently playing audio.
+    fn get_app_volume(&self, _app: &str) -> anyhow::Result<Option<f32>> {
+        Err(anyhow::anyhow!(
+            "per-application volume is not supported by this backend"
+        ))
+    }
+
+    /// Set the volume of a specific application (0.0 = mute, 1.0 = max
+    /// volume). Returns `false` if the application isn't currently playing
+    /// audio.
+    fn set_app_volume(&self, _app: &str, _vol: f32) -> anyhow::Result<bool> {
+        Err(anyhow::anyhow!(
+            "per-application volume is not supported by this backend"
+        ))
+    }
+
+    /// List all available output devices.
+    fn list_endpoints(&self) -> anyhow::Result<Vec<AudioEndpoint>>;
+
+    /// Change the system's default output device.
+    fn set_default_endpoint(&self, _endpoint: &str) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!(
+            "changing the default output device is not supported by this backend"
+        ))
+    }
+
+    /// Call `on_change` whenever the master volume / mute state changes
+    /// (regardless of who changed it), until the returned [`Watch`] is
+    /// dropped.
+    fn watch(&self, _on_change: OnChange) -> anyhow::Result<Watch> {
+        Err(anyhow::anyhow!(
+            "volume change notifications are not supported by this backend"
+        ))
+    }
+}
+
+/// Check if a process / application name refers to the given app.
+///
+/// The same app goes by different names on different platforms (e.g:
+/// `Spotify.exe` on Windows vs. `spotify` on Linux), so names are compared
+/// case-insensitively, ignoring any `.exe` suffix.
+fn app_name_matches(name: &str, app: &str) -> bool {
+    fn normalize(s: &str) -> String {
+        let s = s.to_lowercase();
+        match s.strip_suffix(".exe") {
+            Some(s) => s.to_string(),
+            None => s,
         }
     }
+
+    normalize(name) == normalize(app)
+}
+
+/// Which [`VolumeBackend`] to use.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ArgEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Pick the most appropriate backend for the current platform
+    #[default]
+    Auto,
+    /// Windows Core Audio
+    Windows,
+    /// PulseAudio (or PipeWire, via `pipewire-pulse`)
+    Pulse,
+    /// ALSA mixer (for boxes without a sound server)
+    Alsa,
+    /// In-memory mock, which doesn't touch the system's audio at all
+    Mock,
+}
+
+/// Async stream of volume / mute state changes, returned by
+/// [`VolumeController::subscribe`].
+pub struct VolumeEvents {
+    events: mpsc::UnboundedReceiver<VolumeState>,
+    _watch: Watch,
+}
+
+impl tokio_stream::Stream for VolumeEvents {
+    type Item = VolumeState;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<VolumeState>> {
+        self.events.poll_recv(cx)
+    }
 }
 
 /// Control system audio
-pub struct VolumeController(sys::VolumeControllerImpl);
+pub struct VolumeController(Arc<dyn VolumeBackend>);
 
 impl VolumeController {
-    /// Construct a new [`AudioCtl`] to control the system's default audio
-    /// endpoint.
-    pub fn new_system_default() -> anyhow::Result<Self> {
-        Ok(VolumeController(
-            sys::VolumeControllerImpl::new_system_default()?,
-        ))
+    /// Construct a new [`VolumeController`] to control the output device
+    /// specified by `opts` (or the system's default output device), using
+    /// whatever backend `opts` asks for.
+    pub fn new(opts: &SystemAudio) -> anyhow::Result<Self> {
+        let backend = opts.backend.unwrap_or_default();
+        let device = opts.audio_device.as_deref();
+
+        let ctl = match backend {
+            Backend::Auto => return Self::new_auto(opts),
+            #[cfg(windows)]
+            Backend::Windows => {
+                VolumeController::from_backend(windows::WindowsVolume::new(device)?)
+            }
+            #[cfg(target_os = "linux")]
+            Backend::Pulse => VolumeController::from_backend(pulse::PulseVolume::new(device)?),
+            #[cfg(target_os = "linux")]
+            Backend::Alsa => VolumeController::from_backend(alsa::AlsaVolume::new(
+                device.or(opts.alsa_card.as_deref()),
+                opts.alsa_control.as_deref(),
+            )?),
+            Backend::Mock => VolumeController::from_backend(mock::MockVolume::new(0.5)),
+            #[allow(unreachable_patterns)]
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "the {:?} volume backend is not available on this platform",
+                    backend
+                ))
+            }
+        };
+
+        Ok(ctl)
+    }
+
+    #[cfg(windows)]
+    fn new_auto(opts: &SystemAudio) -> anyhow::Result<Self> {
+        Ok(VolumeController::from_backend(windows::WindowsVolume::new(
+            opts.audio_device.as_deref(),
+        )?))
+    }
+
+    // prefer talking to a sound server (PulseAudio / PipeWire), falling back to
+    // poking the ALSA mixer directly if there isn't one running.
+    #[cfg(target_os = "linux")]
+    fn new_auto(opts: &SystemAudio) -> anyhow::Result<Self> {
+        let device = opts.audio_device.as_deref();
+
+        match pulse::check_server() {
+            Ok(()) => Ok(VolumeController::from_backend(pulse::PulseVolume::new(
+                device,
+            )?)),
+            Err(e) => {
+                log::info!("{:#}. falling back to ALSA mixer", e);
+                Ok(VolumeController::from_backend(alsa::AlsaVolume::new(
+                    device.or(opts.alsa_card.as_deref()),
+                    opts.alsa_control.as_deref(),
+                )?))
+            }
+        }
+    }
+
+    #[cfg(not(any(windows, target_os = "linux")))]
+    fn new_auto(_opts: &SystemAudio) -> anyhow::Result<Self> {
+        Err(anyhow::anyhow!("no volume controller is currently implemented for this platform. consider opening a PR?"))
+    }
+
+    /// Construct a new [`VolumeController`] using an explicit backend.
+    pub fn from_backend(backend: impl VolumeBackend + 'static) -> Self {
+        VolumeController(Arc::new(backend))
     }
 
     /// Get the master volume (0.0 = mute, 1.0 = max volume)
     pub fn get_master_volume(&self) -> anyhow::Result<f32> {
-        self.0.get_master_volume().map_err(Into::into)
+        self.0.get_master_volume()
     }
 
     /// Set the master volume (0.0 = mute, 1.0 = max volume)
     pub fn set_master_volume(&self, vol: f32) -> anyhow::Result<()> {
-        self.0.set_master_volume(vol).map_err(Into::into)
+        self.0.set_master_volume(vol)
+    }
+
+    /// Check if the output is muted
+    pub fn get_mute(&self) -> anyhow::Result<bool> {
+        self.0.get_mute()
+    }
+
+    /// Mute / unmute the output
+    pub fn set_mute(&self, muted: bool) -> anyhow::Result<()> {
+        self.0.set_mute(muted)
     }
+
+    /// Get the volume of a specific application (0.0 = mute, 1.0 = max
+    /// volume), or `None` if the application isn't currently playing audio.
+    pub fn get_app_volume(&self, app: &str) -> anyhow::Result<Option<f32>> {
+        self.0.get_app_volume(app)
+    }
+
+    /// Set the volume of a specific application (0.0 = mute, 1.0 = max
+    /// volume). Returns `false` if the application isn't currently playing
+    /// audio.
+    pub fn set_app_volume(&self, app: &str, vol: f32) -> anyhow::Result<bool> {
+        self.0.set_app_volume(app, vol)
+    }
+
+    /// List all available output devices.
+    pub fn list_endpoints(&self) -> anyhow::Result<Vec<AudioEndpoint>> {
+        self.0.list_endpoints()
+    }
+
+    /// Change the system's default output device (by name or ID).
+    ///
+    /// Unless the controller was explicitly told which output device to use,
+    /// it will follow the system's default output device.
+    pub fn set_default_endpoint(&self, endpoint: &str) -> anyhow::Result<()> {
+        self.0.set_default_endpoint(endpoint)
+    }
+
+    /// Get the master volume and mute state in one go.
+    pub fn get_state(&self) -> anyhow::Result<VolumeState> {
+        Ok(VolumeState {
+            volume: self.get_master_volume()?,
+            muted: self.get_mute()?,
+        })
+    }
+
+    /// Subscribe to volume / mute state changes (regardless of who made
+    /// them). Changes stop being tracked once the returned stream is dropped.
+    ///
+    /// If the backend can't notify us of changes, the state is polled for
+    /// changes instead.
+    pub fn subscribe(&self) -> anyhow::Result<VolumeEvents> {
+        let (tx, events) = mpsc::unbounded_channel();
+        let on_change: OnChange = Arc::new(move |state| {
+            let _ = tx.send(state);
+        });
+
+        let watch = match self.0.watch(on_change.clone()) {
+            Ok(watch) => watch,
+            Err(e) => {
+                log::info!("{:#}. falling back to polling", e);
+                poll_for_changes(self.0.clone(), on_change)
+            }
+        };
+
+        Ok(VolumeEvents {
+            events,
+            _watch: watch,
+        })
+    }
+}
+
+/// Poll `backend` for changes on a background thread, until the returned
+/// [`Watch`] is dropped.
+fn poll_for_changes(backend: Arc<dyn VolumeBackend>, on_change: OnChange) -> Watch {
+    struct StopOnDrop(Arc<AtomicBool>);
+
+    impl Drop for StopOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    std::thread::spawn({
+        let stop = stop.clone();
+        move || {
+            let state = || -> anyhow::Result<VolumeState> {
+                Ok(VolumeState {
+                    volume: backend.get_master_volume()?,
+                    muted: backend.get_mute()?,
+                })
+            };
+
+            let mut last = state().ok();
+            while !stop.load(Ordering::Relaxed) {
+                std::thread::sleep(POLL_INTERVAL);
+                match state() {
+                    Ok(current) if last != Some(current) => {
+                        last = Some(current);
+                        on_change(current);
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::warn!("could not poll volume: {:#}", e),
+                }
+            }
+        }
+    });
+
+    Watch::new(StopOnDrop(stop))
 }
@@ -0,0 +1,549 @@
+use super::AudioEndpoint;
+use super::OnChange;
+use super::VolumeBackend;
+use super::VolumeState;
+use super::Watch;
+use anyhow::anyhow;
+use anyhow::Context;
+use std::io::BufRead;
+use std::process::Child;
+use std::process::Command;
+use std::process::Stdio;
+
+/// PulseAudio's "100%" volume (`PA_VOLUME_NORM`)
+const PA_VOLUME_NORM: f32 = 0x10000 as f32;
+
+/// Talks to PulseAudio (or PipeWire, via `pipewire-pulse`) by shelling out to
+/// `pactl`.
+///
+/// Going through `pactl` instead of linking against `libpulse` means there's
+/// no build-time dependency on the pulse headers, and no need to drive the
+/// pulse mainloop just to poke at a single number.
+pub struct PulseVolume {
+    sink: String,
+}
+
+/// Check that there's actually a PulseAudio server to talk to.
+pub fn check_server() -> anyhow::Result<()> {
+    pactl(&["info"]).context("could not connect to PulseAudio server")?;
+    Ok(())
+}
+
+impl PulseVolume {
+    /// `sink` can be either the sink's name, or its description. If it's
+    /// `None`, the default sink is used.
+    pub fn new(sink: Option<&str>) -> anyhow::Result<PulseVolume> {
+        check_server()?;
+
+        let sink = match sink {
+            None => "@DEFAULT_SINK@".into(),
+            Some(sink) => list_sinks()?
+                .into_iter()
+                .find(|s| s.matches(sink))
+                .map(|s| s.id)
+                .ok_or_else(|| anyhow!("could not find audio device {:?}", sink))?,
+        };
+
+        Ok(PulseVolume { sink })
+    }
+}
+
+impl PulseVolume {
+    /// Index of the sink being controlled (which is how sink inputs refer to
+    /// the sink they're playing on).
+    ///
+    /// Looked up fresh each time, since `@DEFAULT_SINK@` may have changed, and
+    /// indices aren't stable across sinks coming and going.
+    fn sink_index(&self) -> anyhow::Result<u32> {
+        let name = if self.sink == "@DEFAULT_SINK@" {
+            let info = pactl(&["info"])?;
+            default_sink(&info)
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("PulseAudio server has no default sink"))?
+        } else {
+            self.sink.clone()
+        };
+
+        // "0\talsa_output.pci-0000_00_1f.3.analog-stereo\tmodule-alsa-card.c\t..."
+        let out = pactl(&["list", "short", "sinks"])?;
+        out.lines()
+            .find_map(|l| {
+                let mut fields = l.split('\t');
+                let (index, sink) = (fields.next()?, fields.next()?);
+                if sink == name {
+                    index.parse().ok()
+                } else {
+                    None
+                }
+            })
+            .ok_or_else(|| anyhow!("could not find audio device {:?}", name))
+    }
+}
+
+impl VolumeBackend for PulseVolume {
+    fn get_master_volume(&self) -> anyhow::Result<f32> {
+        let out = pactl(&["get-sink-volume", &self.sink])?;
+        parse_volume(&out)
+    }
+
+    fn set_master_volume(&self, vol: f32) -> anyhow::Result<()> {
+        let raw = (vol.clamp(0.0, 1.0) * PA_VOLUME_NORM).round() as u32;
+        pactl(&["set-sink-volume", &self.sink, &raw.to_string()])?;
+        Ok(())
+    }
+
+    fn get_mute(&self) -> anyhow::Result<bool> {
+        let out = pactl(&["get-sink-mute", &self.sink])?;
+        parse_mute(&out)
+    }
+
+    fn set_mute(&self, muted: bool) -> anyhow::Result<()> {
+        pactl(&["set-sink-mute", &self.sink, if muted { "1" } else { "0" }])?;
+        Ok(())
+    }
+
+    fn get_app_volume(&self, app: &str) -> anyhow::Result<Option<f32>> {
+        let mut vol = None;
+        for input in app_sink_inputs(app, self.sink_index()?)? {
+            vol = Some(input.volume.max(vol.unwrap_or(0.0)));
+        }
+        Ok(vol)
+    }
+
+    fn set_app_volume(&self, app: &str, vol: f32) -> anyhow::Result<bool> {
+        let raw = (vol.clamp(0.0, 1.0) * PA_VOLUME_NORM).round() as u32;
+        let inputs = app_sink_inputs(app, self.sink_index()?)?;
+        for input in &inputs {
+            pactl(&[
+                "set-sink-input-volume",
+                &input.index.to_string(),
+                &raw.to_string(),
+            ])?;
+        }
+        Ok(!inputs.is_empty())
+    }
+
+    fn list_endpoints(&self) -> anyhow::Result<Vec<AudioEndpoint>> {
+        list_sinks()
+    }
+
+    fn set_default_endpoint(&self, endpoint: &str) -> anyhow::Result<()> {
+        let sink = list_sinks()?
+            .into_iter()
+            .find(|s| s.matches(endpoint))
+            .ok_or_else(|| anyhow!("could not find audio device {:?}", endpoint))?;
+
+        // streams which haven't explicitly picked a sink get moved over to the new
+        // default sink automatically, and `@DEFAULT_SINK@` will now refer to
+        // the new sink as well.
+        pactl(&["set-default-sink", &sink.id])?;
+        Ok(())
+    }
+
+    fn watch(&self, on_change: OnChange) -> anyhow::Result<Watch> {
+        let mut child = Command::new("pactl")
+            .arg("subscribe")
+            .env("LC_ALL", "C")
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("failed to run `pactl subscribe` (is it installed?)")?;
+        let stdout = child.stdout.take().unwrap();
+
+        let pulse = PulseVolume {
+            sink: self.sink.clone(),
+        };
+        let state = move || -> anyhow::Result<VolumeState> {
+            Ok(VolumeState {
+                volume: pulse.get_master_volume()?,
+                muted: pulse.get_mute()?,
+            })
+        };
+        let mut last = state().ok();
+
+        std::thread::spawn(move || {
+            // "Event 'change' on sink #0"
+            //
+            // pactl doesn't say _what_ changed, so just check if the volume / mute
+            // state is any different. "server" events are included, since those
+            // fire when the default sink changes.
+            for line in std::io::BufReader::new(stdout).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                if !(line.contains("on sink #") || line.contains("on server")) {
+                    continue;
+                }
+
+                match state() {
+                    Ok(current) if last != Some(current) => {
+                        last = Some(current);
+                        on_change(current);
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::warn!("could not get volume after change: {:#}", e),
+                }
+            }
+        });
+
+        Ok(Watch::new(PulseWatch(child)))
+    }
+}
+
+/// Stops `pactl subscribe` when dropped (which in turn stops the thread
+/// reading its output).
+struct PulseWatch(Child);
+
+impl Drop for PulseWatch {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn pactl(args: &[&str]) -> anyhow::Result<String> {
+    let output = Command::new("pactl")
+        .args(args)
+        // pactl localizes its output, which would make it a pain to parse
+        .env("LC_ALL", "C")
+        .output()
+        .context("failed to run `pactl` (is it installed?)")?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`pactl {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// List all sinks, using the sink name as the endpoint ID, and the sink
+/// description as the endpoint name.
+fn list_sinks() -> anyhow::Result<Vec<AudioEndpoint>> {
+    let info = pactl(&["info"])?;
+    let out = pactl(&["list", "sinks"])?;
+    parse_sinks(&out, default_sink(&info))
+}
+
+/// Find the default sink's name in the output of `pactl info`, which has a
+/// line like:
+///
+/// ```text
+/// Default Sink: alsa_output.pci-0000_00_1f.3.analog-stereo
+/// ```
+fn default_sink(info: &str) -> Option<&str> {
+    info.lines()
+        .find_map(|l| l.strip_prefix("Default Sink:"))
+        .map(str::trim)
+}
+
+/// Parse the output of `pactl list sinks`, which looks something like:
+///
+/// ```text
+/// Sink #0
+///     State: RUNNING
+///     Name: alsa_output.pci-0000_00_1f.3.analog-stereo
+///     Description: Built-in Audio Analog Stereo
+///     Driver: module-alsa-card.c
+///     ...
+/// ```
+fn parse_sinks(s: &str, default_sink: Option<&str>) -> anyhow::Result<Vec<AudioEndpoint>> {
+    s.split("Sink #")
+        .skip(1)
+        .map(|block| {
+            let field = |name: &str| {
+                block
+                    .lines()
+                    .find_map(|l| l.trim().strip_prefix(name))
+                    .map(|v| v.trim().to_string())
+                    .ok_or_else(|| anyhow!("unexpected `pactl` sink: {:?}", block))
+            };
+
+            let id = field("Name:")?;
+            Ok(AudioEndpoint {
+                is_default: Some(id.as_str()) == default_sink,
+                name: field("Description:")?,
+                id,
+            })
+        })
+        .collect()
+}
+
+/// A playback stream (i.e: an application playing audio).
+struct SinkInput {
+    index: u32,
+    /// Index of the sink it's playing on
+    sink: Option<u32>,
+    volume: f32,
+    /// `application.name` and `application.process.binary`
+    names: Vec<String>,
+}
+
+/// Return all the sink inputs belonging to `app` which are playing on the
+/// given sink.
+fn app_sink_inputs(app: &str, sink: u32) -> anyhow::Result<Vec<SinkInput>> {
+    let out = pactl(&["list", "sink-inputs"])?;
+    Ok(parse_sink_inputs(&out)?
+        .into_iter()
+        .filter(|input| input.sink == Some(sink))
+        .filter(|input| {
+            input
+                .names
+                .iter()
+                .any(|name| super::app_name_matches(name, app))
+        })
+        .collect())
+}
+
+/// Parse the output of `pactl list sink-inputs`, which looks something like:
+///
+/// ```text
+/// Sink Input #42
+///     Driver: protocol-native.c
+///     Sink: 0
+///     Mute: no
+///     Volume: front-left: 65536 / 100% / 0.00 dB,   front-right: 65536 / 100% / 0.00 dB
+///             balance 0.00
+///     Properties:
+///         application.name = "Spotify"
+///         application.process.binary = "spotify"
+/// ```
+fn parse_sink_inputs(s: &str) -> anyhow::Result<Vec<SinkInput>> {
+    s.split("Sink Input #")
+        .skip(1)
+        .map(|block| {
+            let index = block
+                .lines()
+                .next()
+                .and_then(|l| l.trim().parse().ok())
+                .ok_or_else(|| anyhow!("unexpected `pactl` sink input: {:?}", block))?;
+
+            let names = block
+                .lines()
+                .filter_map(|l| l.trim().split_once(" = "))
+                .filter(|(key, _)| {
+                    matches!(*key, "application.name" | "application.process.binary")
+                })
+                .map(|(_, val)| val.trim_matches('"').to_string())
+                .collect();
+
+            let sink = block
+                .lines()
+                .find_map(|l| l.trim().strip_prefix("Sink:"))
+                .and_then(|s| s.trim().parse().ok());
+
+            Ok(SinkInput {
+                index,
+                sink,
+                volume: parse_volume(block)?,
+                names,
+            })
+        })
+        .collect()
+}
+
+/// Parse the output of `pactl get-sink-mute`, which looks like `Mute: yes`.
+fn parse_mute(s: &str) -> anyhow::Result<bool> {
+    match s.trim().strip_prefix("Mute:").map(str::trim) {
+        Some("yes") => Ok(true),
+        Some("no") => Ok(false),
+        _ => Err(anyhow!("unexpected `pactl` output: {:?}", s)),
+    }
+}
+
+/// Parse the output of `pactl get-sink-volume`, which looks something like:
+///
+/// ```text
+/// Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: 32768 /  50% / -18.06 dB
+///         balance 0.00
+/// ```
+///
+/// Pulse tracks volume per-channel, so (just like pulse's own "overall volume")
+/// the loudest channel is reported as the master volume.
+fn parse_volume(s: &str) -> anyhow::Result<f32> {
+    let line = s
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("Volume:"))
+        .ok_or_else(|| anyhow!("unexpected `pactl` output: {:?}", s))?;
+
+    let raw = line
+        .split(',')
+        .map(|chan| {
+            // "front-left: 32768 /  50% / -18.06 dB"
+            chan.split(':')
+                .nth(1)
+                .and_then(|v| v.split('/').next())
+                .and_then(|v| v.trim().parse::<u32>().ok())
+                .ok_or_else(|| anyhow!("unexpected `pactl` channel volume: {:?}", chan))
+        })
+        .try_fold(0, |max, raw| raw.map(|raw| max.max(raw)))?;
+
+    Ok((raw as f32 / PA_VOLUME_NORM).min(1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // captured via `LC_ALL=C pactl info`
+    const INFO: &str = "\
+Server String: /run/user/1000/pulse/native
+Library Protocol Version: 35
+Server Protocol Version: 35
+Is Local: yes
+Client Index: 87
+Tile Size: 65472
+User Name: daniel
+Host Name: desktop
+Server Name: PulseAudio (on PipeWire 0.3.65)
+Server Version: 15.0.0
+Default Sample Specification: float32le 2ch 48000Hz
+Default Channel Map: front-left,front-right
+Default Sink: alsa_output.usb-Schiit_Audio_Schiit_Modi_3-00.analog-stereo
+Default Source: alsa_input.pci-0000_00_1f.3.analog-stereo
+Cookie: 8d2f:1c3a
+";
+
+    // captured via `LC_ALL=C pactl list sinks` (trimmed)
+    const SINKS: &str = "\
+Sink #46
+\tState: SUSPENDED
+\tName: alsa_output.pci-0000_00_1f.3.analog-stereo
+\tDescription: Built-in Audio Analog Stereo
+\tDriver: PipeWire
+\tSample Specification: s32le 2ch 48000Hz
+\tChannel Map: front-left,front-right
+\tOwner Module: 4294967295
+\tMute: no
+\tVolume: front-left: 42598 /  65% / -11.23 dB,   front-right: 42598 /  65% / -11.23 dB
+\t        balance 0.00
+
+Sink #58
+\tState: RUNNING
+\tName: alsa_output.usb-Schiit_Audio_Schiit_Modi_3-00.analog-stereo
+\tDescription: Modi 3 Analog Stereo
+\tDriver: PipeWire
+\tSample Specification: s24le 2ch 48000Hz
+\tChannel Map: front-left,front-right
+\tOwner Module: 4294967295
+\tMute: yes
+\tVolume: front-left: 65536 / 100% / 0.00 dB,   front-right: 65536 / 100% / 0.00 dB
+\t        balance 0.00
+";
+
+    // captured via `LC_ALL=C pactl list sink-inputs` (trimmed)
+    const SINK_INPUTS: &str = "\
+Sink Input #42
+\tDriver: protocol-native.c
+\tOwner Module: 12
+\tClient: 31
+\tSink: 58
+\tSample Specification: float32le 2ch 44100Hz
+\tChannel Map: front-left,front-right
+\tCorked: no
+\tMute: no
+\tVolume: front-left: 49152 /  75% / -7.50 dB,   front-right: 32768 /  50% / -18.06 dB
+\t        balance -0.33
+\tBuffer Latency: 0 usec
+\tSink Latency: 0 usec
+\tResample method: n/a
+\tProperties:
+\t\tmedia.name = \"Spotify\"
+\t\tapplication.name = \"Spotify\"
+\t\tapplication.process.binary = \"spotify\"
+
+Sink Input #43
+\tDriver: protocol-native.c
+\tOwner Module: 12
+\tClient: 35
+\tSink: 46
+\tSample Specification: s16le 1ch 48000Hz
+\tChannel Map: mono
+\tCorked: no
+\tMute: yes
+\tVolume: mono: 32768 /  50% / -18.06 dB
+\t        balance 0.00
+\tProperties:
+\t\tapplication.name = \"Firefox\"
+\t\tapplication.process.binary = \"firefox\"
+";
+
+    #[test]
+    fn volume() {
+        let stereo = "Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: 32768 /  50% / -18.06 dB\n        balance 0.00\n";
+        assert_eq!(parse_volume(stereo).unwrap(), 0.5);
+
+        let mono = "Volume: mono: 65536 / 100% / 0.00 dB\n        balance 0.00\n";
+        assert_eq!(parse_volume(mono).unwrap(), 1.0);
+
+        // 5.1, with the loudest channel reported
+        let surround = "Volume: front-left: 19661 /  30% / -31.37 dB,   front-right: 19661 /  30% / -31.37 dB,   rear-left: 13107 /  20% / -41.94 dB,   rear-right: 13107 /  20% / -41.94 dB,   front-center: 49152 /  75% / -7.50 dB,   lfe: 0 /   0% / -inf dB\n        balance 0.00\n";
+        assert_eq!(parse_volume(surround).unwrap(), 0.75);
+
+        // boosted past 100%
+        let boosted = "Volume: mono: 98304 / 150% / 10.57 dB\n";
+        assert_eq!(parse_volume(boosted).unwrap(), 1.0);
+
+        assert!(parse_volume("").is_err());
+        assert!(parse_volume("Volume: front-left: loud").is_err());
+    }
+
+    #[test]
+    fn mute() {
+        assert!(parse_mute("Mute: yes\n").unwrap());
+        assert!(!parse_mute("Mute: no\n").unwrap());
+        assert!(parse_mute("Stummschalten: ja\n").is_err());
+    }
+
+    #[test]
+    fn sinks() {
+        let sinks = parse_sinks(SINKS, default_sink(INFO)).unwrap();
+        let sinks: Vec<_> = sinks
+            .iter()
+            .map(|s| (s.id.as_str(), s.name.as_str(), s.is_default))
+            .collect();
+        assert_eq!(
+            sinks,
+            [
+                (
+                    "alsa_output.pci-0000_00_1f.3.analog-stereo",
+                    "Built-in Audio Analog Stereo",
+                    false
+                ),
+                (
+                    "alsa_output.usb-Schiit_Audio_Schiit_Modi_3-00.analog-stereo",
+                    "Modi 3 Analog Stereo",
+                    true
+                ),
+            ]
+        );
+
+        assert!(parse_sinks("", None).unwrap().is_empty());
+        assert!(parse_sinks("Sink #0\n\tState: RUNNING\n", None).is_err());
+    }
+
+    #[test]
+    fn sink_inputs() {
+        let inputs = parse_sink_inputs(SINK_INPUTS).unwrap();
+        assert_eq!(inputs.len(), 2);
+
+        let spotify = &inputs[0];
+        assert_eq!(spotify.index, 42);
+        assert_eq!(spotify.sink, Some(58));
+        assert_eq!(spotify.volume, 0.75);
+        assert_eq!(spotify.names, ["Spotify", "spotify"]);
+
+        // muted, mono
+        let firefox = &inputs[1];
+        assert_eq!(firefox.index, 43);
+        assert_eq!(firefox.sink, Some(46));
+        assert_eq!(firefox.volume, 0.5);
+        assert_eq!(firefox.names, ["Firefox", "firefox"]);
+
+        assert!(parse_sink_inputs("").unwrap().is_empty());
+        assert!(parse_sink_inputs("Sink Input #nope\n").is_err());
+    }
+}
@@ -1,10 +1,22 @@
 use self::winguids::*;
+use super::AudioEndpoint;
+use super::OnChange;
+use super::VolumeBackend;
+use super::VolumeState;
+use super::Watch;
 use core::mem::MaybeUninit;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::RwLock;
 use windows::core::*;
+use windows::Win32::Devices::FunctionDiscovery::*;
 use windows::Win32::Foundation::*;
 use windows::Win32::Media::Audio::Endpoints::*;
 use windows::Win32::Media::Audio::*;
+use windows::Win32::System::Com::StructuredStorage::*;
 use windows::Win32::System::Com::*;
+use windows::Win32::System::Threading::*;
 
 mod winguids {
     #![allow(non_upper_case_globals, dead_code)]
@@ -24,28 +36,121 @@ mod winguids {
         data3: 0x484f,
         data4: [0x8b, 0xc7, 0x2c, 0x65, 0x4c, 0x9a, 0x9b, 0x6f],
     };
+
+    pub const CLSID_PolicyConfigClient: GUID = GUID {
+        data1: 0x870af99c,
+        data2: 0x171d,
+        data3: 0x4f9e,
+        data4: [0xaf, 0x0d, 0xe6, 0x3d, 0xf4, 0x0c, 0x2b, 0xc9],
+    };
+
+    pub const IID_IPolicyConfig: GUID = GUID {
+        data1: 0xf8679f50,
+        data2: 0x850a,
+        data3: 0x41cf,
+        data4: [0x9c, 0x72, 0x43, 0x0f, 0x29, 0x02, 0x90, 0xc8],
+    };
 }
 
-pub struct VolumeControllerImpl {
+pub struct WindowsVolume {
+    /// Whether the endpoint was explicitly picked, as opposed to just being
+    /// whatever the system default was at the time.
+    pinned: bool,
+    endpoint: Arc<RwLock<Endpoint>>,
+}
+
+/// The endpoint currently being controlled.
+struct Endpoint {
+    device: IMMDevice,
     volume: IAudioEndpointVolume,
+    /// Change notification callbacks registered on `volume` (which need to be
+    /// moved over whenever the endpoint changes).
+    callbacks: Vec<IAudioEndpointVolumeCallback>,
 }
 
-impl VolumeControllerImpl {
+impl Endpoint {
+    unsafe fn open(device: IMMDevice) -> Result<Endpoint> {
+        let volume = {
+            let mut obj: MaybeUninit<IAudioEndpointVolume> = MaybeUninit::uninit();
+            device.Activate(
+                &IID_IAudioEndpointVolume,
+                CLSCTX_ALL,
+                core::ptr::null(),
+                obj.as_mut_ptr() as _,
+            )?;
+            obj.assume_init()
+        };
+
+        Ok(Endpoint {
+            device,
+            volume,
+            callbacks: Vec::new(),
+        })
+    }
+
+    /// Switch over to `device`, taking any registered callbacks along with it.
+    unsafe fn switch_to(&mut self, device: IMMDevice) -> Result<()> {
+        let mut new = Endpoint::open(device)?;
+        for callback in &self.callbacks {
+            new.volume.RegisterControlChangeNotify(callback)?;
+            new.callbacks.push(callback.clone());
+        }
+        for callback in &self.callbacks {
+            let _ = self.volume.UnregisterControlChangeNotify(callback);
+        }
+        *self = new;
+        Ok(())
+    }
+}
+
+// SAFETY: COM is initialized using the multithreaded apartment, and both
+// IMMDevice and IAudioEndpointVolume are free-threaded. The callback objects
+// are free-threaded too.
+unsafe impl Send for Endpoint {}
+unsafe impl Sync for Endpoint {}
+
+impl WindowsVolume {
     // this was my first time doing COM programming, and lemme tell ya, it sure is
     // *something* alright...
-    pub fn new_system_default() -> Result<VolumeControllerImpl> {
+    //
+    // `endpoint` can be either the endpoint's ID, or its friendly name. If it's
+    // `None`, the system's default endpoint is used.
+    pub fn new(endpoint: Option<&str>) -> anyhow::Result<WindowsVolume> {
         unsafe {
             CoInitializeEx(std::ptr::null_mut(), COINIT_MULTITHREADED)?;
 
             let device_enumerator: IMMDeviceEnumerator =
                 CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
 
-            let device = device_enumerator.GetDefaultAudioEndpoint(eRender, eMultimedia)?;
+            let device = match endpoint {
+                None => device_enumerator.GetDefaultAudioEndpoint(eRender, eMultimedia)?,
+                Some(endpoint) => find_render_endpoint(&device_enumerator, endpoint)?.0,
+            };
+
+            Ok(WindowsVolume {
+                pinned: endpoint.is_some(),
+                endpoint: Arc::new(RwLock::new(Endpoint::open(device)?)),
+            })
+        }
+    }
+
+    fn device(&self) -> IMMDevice {
+        self.endpoint.read().unwrap().device.clone()
+    }
+
+    fn volume(&self) -> IAudioEndpointVolume {
+        self.endpoint.read().unwrap().volume.clone()
+    }
 
-            let volume = {
-                let mut obj: MaybeUninit<IAudioEndpointVolume> = MaybeUninit::uninit();
-                device.Activate(
-                    &IID_IAudioEndpointVolume,
+    /// Return the volume controls for every audio session belonging to `app`.
+    fn app_sessions(&self, app: &str) -> Result<Vec<ISimpleAudioVolume>> {
+        unsafe {
+            // sessions come and go, so the session manager is queried fresh
+            // each time
+            let audio_session_manager = {
+                let mut obj: MaybeUninit<IAudioSessionManager2> = MaybeUninit::uninit();
+                self.device().Activate(
+                    &IID_IAudioSessionManager2,
                     CLSCTX_ALL,
                     core::ptr::null(),
                     obj.as_mut_ptr() as _,
@@ -53,45 +158,334 @@ impl VolumeControllerImpl {
                 obj.assume_init()
             };
 
-            // let audio_session_manager = {
-            //     let mut obj: MaybeUninit<IAudioSessionManager2> = MaybeUninit::uninit();
-            //     device.Activate(
-            //         &IID_IAudioSessionManager2,
-            //         CLSCTX_ALL,
-            //         core::ptr::null(),
-            //         obj.as_mut_ptr() as _,
-            //     )?;
-            //     obj.assume_init()
-            // };
-
-            // let session_enumerator = audio_session_manager.GetSessionEnumerator()?;
-
-            // for session in
-            //     (0..session_enumerator.GetCount()?).map(|id|
-            // session_enumerator.GetSession(id)) {
-            //     let session = session?;
-
-            //     let name = read_to_string(session.GetDisplayName()?);
-            //     dbg!(name);
-            // }
-
-            Ok(VolumeControllerImpl { volume })
+            let session_enumerator = audio_session_manager.GetSessionEnumerator()?;
+
+            let mut sessions = Vec::new();
+            for id in 0..session_enumerator.GetCount()? {
+                let session: IAudioSessionControl2 = session_enumerator.GetSession(id)?.cast()?;
+
+                // the "system sounds" session belongs to pid 0
+                let pid = session.GetProcessId()?;
+                if pid == 0 {
+                    continue;
+                }
+
+                match process_name(pid) {
+                    Some(name) if super::app_name_matches(&name, app) => {
+                        sessions.push(session.cast()?)
+                    }
+                    _ => {}
+                }
+            }
+
+            Ok(sessions)
+        }
+    }
+}
+
+impl VolumeBackend for WindowsVolume {
+    fn get_master_volume(&self) -> anyhow::Result<f32> {
+        unsafe { Ok(self.volume().GetMasterVolumeLevelScalar()?) }
+    }
+
+    fn set_master_volume(&self, vol: f32) -> anyhow::Result<()> {
+        unsafe {
+            self.volume()
+                .SetMasterVolumeLevelScalar(vol, core::ptr::null())?;
+        }
+        Ok(())
+    }
+
+    fn get_mute(&self) -> anyhow::Result<bool> {
+        unsafe { Ok(self.volume().GetMute()?.as_bool()) }
+    }
+
+    fn set_mute(&self, muted: bool) -> anyhow::Result<()> {
+        unsafe {
+            self.volume().SetMute(muted, core::ptr::null())?;
+        }
+        Ok(())
+    }
+
+    fn get_app_volume(&self, app: &str) -> anyhow::Result<Option<f32>> {
+        let mut vol = None;
+        for session in self.app_sessions(app)? {
+            let session_vol = unsafe { session.GetMasterVolume()? };
+            vol = Some(session_vol.max(vol.unwrap_or(0.0)));
+        }
+        Ok(vol)
+    }
+
+    fn set_app_volume(&self, app: &str, vol: f32) -> anyhow::Result<bool> {
+        let sessions = self.app_sessions(app)?;
+        for session in &sessions {
+            unsafe { session.SetMasterVolume(vol, core::ptr::null())? };
+        }
+        Ok(!sessions.is_empty())
+    }
+
+    fn list_endpoints(&self) -> anyhow::Result<Vec<AudioEndpoint>> {
+        unsafe {
+            let device_enumerator: IMMDeviceEnumerator =
+                CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+
+            let default_id =
+                device_id(&device_enumerator.GetDefaultAudioEndpoint(eRender, eMultimedia)?)?;
+
+            Ok(render_endpoints(&device_enumerator)?
+                .into_iter()
+                .map(|(_, mut info)| {
+                    info.is_default = info.id == default_id;
+                    info
+                })
+                .collect())
+        }
+    }
+
+    fn set_default_endpoint(&self, endpoint: &str) -> anyhow::Result<()> {
+        unsafe {
+            let device_enumerator: IMMDeviceEnumerator =
+                CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+
+            let (device, info) = find_render_endpoint(&device_enumerator, endpoint)?;
+
+            let policy_config: IPolicyConfig =
+                CoCreateInstance(&CLSID_PolicyConfigClient, None, CLSCTX_ALL)?;
+
+            let id = info.id.encode_utf16().chain(Some(0)).collect::<Vec<u16>>();
+            for role in [eConsole, eMultimedia, eCommunications] {
+                policy_config.set_default_endpoint(id.as_ptr(), role)?;
+            }
+
+            // if we were just following the system default, keep following it
+            if !self.pinned {
+                self.endpoint.write().unwrap().switch_to(device)?;
+            }
+
+            Ok(())
         }
     }
 
-    pub fn get_master_volume(&self) -> Result<f32> {
-        unsafe { self.volume.GetMasterVolumeLevelScalar() }
+    fn watch(&self, on_change: OnChange) -> anyhow::Result<Watch> {
+        let callback = VolumeCallback::create(on_change);
+        let mut endpoint = self.endpoint.write().unwrap();
+        unsafe { endpoint.volume.RegisterControlChangeNotify(&callback)? };
+        endpoint.callbacks.push(callback.clone());
+        Ok(Watch::new(WindowsWatch {
+            endpoint: self.endpoint.clone(),
+            callback,
+        }))
     }
+}
+
+/// Unregisters a [`VolumeCallback`] (from whichever endpoint it ended up on)
+/// when dropped.
+struct WindowsWatch {
+    endpoint: Arc<RwLock<Endpoint>>,
+    callback: IAudioEndpointVolumeCallback,
+}
+
+// SAFETY: see `Endpoint`.
+unsafe impl Send for WindowsWatch {}
 
-    pub fn set_master_volume(&self, vol: f32) -> Result<()> {
+impl Drop for WindowsWatch {
+    fn drop(&mut self) {
+        let mut endpoint = self.endpoint.write().unwrap();
+        endpoint.callbacks.retain(|c| *c != self.callback);
         unsafe {
-            self.volume
-                .SetMasterVolumeLevelScalar(vol, core::ptr::null())
+            let _ = endpoint
+                .volume
+                .UnregisterControlChangeNotify(&self.callback);
         }
     }
 }
 
-#[allow(dead_code)]
+/// Hand-rolled COM object implementing `IAudioEndpointVolumeCallback`, since
+/// the `windows` crate's `#[implement]` macro requires nightly.
+#[repr(C)]
+struct VolumeCallback {
+    vtable: &'static IAudioEndpointVolumeCallback_Vtbl,
+    refs: AtomicU32,
+    on_change: OnChange,
+}
+
+static VOLUME_CALLBACK_VTBL: IAudioEndpointVolumeCallback_Vtbl =
+    IAudioEndpointVolumeCallback_Vtbl {
+        base: IUnknownVtbl {
+            QueryInterface: VolumeCallback::query_interface,
+            AddRef: VolumeCallback::add_ref,
+            Release: VolumeCallback::release,
+        },
+        OnNotify: VolumeCallback::on_notify,
+    };
+
+impl VolumeCallback {
+    fn create(on_change: OnChange) -> IAudioEndpointVolumeCallback {
+        let obj = Box::new(VolumeCallback {
+            vtable: &VOLUME_CALLBACK_VTBL,
+            refs: AtomicU32::new(1),
+            on_change,
+        });
+        // SAFETY: `VolumeCallback` starts with a vtable pointer, just like any
+        // other COM object, and the initial reference is handed off to the
+        // returned interface.
+        unsafe { core::mem::transmute(Box::into_raw(obj)) }
+    }
+
+    unsafe extern "system" fn query_interface(
+        this: RawPtr,
+        iid: &GUID,
+        interface: *mut RawPtr,
+    ) -> HRESULT {
+        if *iid == IUnknown::IID || *iid == IAudioEndpointVolumeCallback::IID {
+            Self::add_ref(this);
+            *interface = this;
+            S_OK
+        } else {
+            *interface = core::ptr::null_mut();
+            E_NOINTERFACE
+        }
+    }
+
+    unsafe extern "system" fn add_ref(this: RawPtr) -> u32 {
+        let this = &*(this as *const VolumeCallback);
+        this.refs.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    unsafe extern "system" fn release(this: RawPtr) -> u32 {
+        let refs = {
+            let this = &*(this as *const VolumeCallback);
+            this.refs.fetch_sub(1, Ordering::AcqRel) - 1
+        };
+        if refs == 0 {
+            drop(Box::from_raw(this as *mut VolumeCallback));
+        }
+        refs
+    }
+
+    unsafe extern "system" fn on_notify(
+        this: RawPtr,
+        data: *mut AUDIO_VOLUME_NOTIFICATION_DATA,
+    ) -> HRESULT {
+        let this = &*(this as *const VolumeCallback);
+        let data = &*data;
+        (this.on_change)(VolumeState {
+            volume: data.fMasterVolume,
+            muted: data.bMuted.as_bool(),
+        });
+        S_OK
+    }
+}
+
+/// Bare-bones binding to the undocumented `IPolicyConfig` interface, which is
+/// the only way to change the default audio endpoint (and is what the Sound
+/// control panel uses under-the-hood).
+///
+/// Only `SetDefaultEndpoint` is bound, as that's all we need.
+#[repr(transparent)]
+#[derive(Clone)]
+struct IPolicyConfig(IUnknown);
+
+#[repr(C)]
+struct IPolicyConfigVtbl {
+    /// IUnknown (3 methods) + GetMixFormat, GetDeviceFormat, ResetDeviceFormat,
+    /// SetDeviceFormat, GetProcessingPeriod, SetProcessingPeriod, GetShareMode,
+    /// SetShareMode, GetPropertyValue, SetPropertyValue
+    _preceding: [usize; 13],
+    set_default_endpoint:
+        unsafe extern "system" fn(this: RawPtr, device_id: *const u16, role: ERole) -> HRESULT,
+}
+
+unsafe impl Interface for IPolicyConfig {
+    type Vtable = IPolicyConfigVtbl;
+    const IID: GUID = IID_IPolicyConfig;
+}
+
+impl IPolicyConfig {
+    unsafe fn set_default_endpoint(&self, device_id: *const u16, role: ERole) -> Result<()> {
+        (self.vtable().set_default_endpoint)(core::mem::transmute_copy(self), device_id, role).ok()
+    }
+}
+
+/// Find an active render endpoint by its ID or friendly name.
+unsafe fn find_render_endpoint(
+    device_enumerator: &IMMDeviceEnumerator,
+    endpoint: &str,
+) -> anyhow::Result<(IMMDevice, AudioEndpoint)> {
+    render_endpoints(device_enumerator)?
+        .into_iter()
+        .find(|(_, info)| info.matches(endpoint))
+        .ok_or_else(|| anyhow::anyhow!("could not find audio device {:?}", endpoint))
+}
+
+/// Return all active render endpoints (i.e: outputs).
+unsafe fn render_endpoints(
+    device_enumerator: &IMMDeviceEnumerator,
+) -> Result<Vec<(IMMDevice, AudioEndpoint)>> {
+    let devices = device_enumerator.EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE)?;
+
+    let mut endpoints = Vec::new();
+    for i in 0..devices.GetCount()? {
+        let device = devices.Item(i)?;
+        let info = AudioEndpoint {
+            id: device_id(&device)?,
+            name: device_name(&device)?,
+            is_default: false,
+        };
+        endpoints.push((device, info))
+    }
+
+    Ok(endpoints)
+}
+
+unsafe fn device_id(device: &IMMDevice) -> Result<String> {
+    let id = device.GetId()?;
+    let s = read_to_string(id);
+    CoTaskMemFree(id.0 as _);
+    Ok(s)
+}
+
+/// Return the device's friendly name (e.g: "Speakers (USB DAC)")
+unsafe fn device_name(device: &IMMDevice) -> Result<String> {
+    let props = device.OpenPropertyStore(STGM_READ)?;
+    let mut name = props.GetValue(&PKEY_Device_FriendlyName)?;
+
+    let pwsz = name.Anonymous.Anonymous.Anonymous.pwszVal;
+    let s = if pwsz.is_null() {
+        String::from("<unknown>")
+    } else {
+        read_to_string(pwsz)
+    };
+
+    PropVariantClear(&mut name)?;
+    Ok(s)
+}
+
+/// Return the executable name (e.g: `Spotify.exe`) of the given process.
+unsafe fn process_name(pid: u32) -> Option<String> {
+    let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid);
+    if process.is_invalid() {
+        return None;
+    }
+
+    let mut buf = [0u16; MAX_PATH as usize];
+    let mut len = buf.len() as u32;
+    let ok = QueryFullProcessImageNameW(
+        process,
+        PROCESS_NAME_WIN32,
+        PWSTR(buf.as_mut_ptr()),
+        &mut len,
+    );
+    CloseHandle(process);
+
+    if !ok.as_bool() {
+        return None;
+    }
+
+    let path = String::from_utf16_lossy(&buf[..len as usize]);
+    path.rsplit('\\').next().map(String::from)
+}
+
 unsafe fn read_to_string(ptr: PWSTR) -> String {
     let mut len = 0usize;
     let mut cursor = ptr;
@@ -105,5 +499,5 @@ unsafe fn read_to_string(ptr: PWSTR) -> String {
     }
 
     let slice = std::slice::from_raw_parts(ptr.0, len);
-    String::from_utf16(slice).unwrap()
+    String::from_utf16_lossy(slice)
 }
@@ -8,25 +8,14 @@ use std::path::PathBuf;
 mod config;
 mod controllers;
 mod rpc;
+mod secrets;
+mod transfer;
 
-#[derive(Debug)]
-enum SyncAudioTo {
-    Remote,
-    Local,
-}
+/// Exit code used when the user needs to run `music-transfer login` (again).
+const EXIT_LOGIN_REQUIRED: i32 = 3;
 
-impl std::str::FromStr for SyncAudioTo {
-    type Err = &'static str;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let res = match s {
-            "local" => SyncAudioTo::Local,
-            "remote" => SyncAudioTo::Remote,
-            _ => return Err("sync-audio-from must be one of 'local' or 'remote'"),
-        };
-        Ok(res)
-    }
-}
+/// How long `mirror` waits before trying to reconnect to the remote server.
+const MIRROR_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
 
 #[derive(Debug, Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -37,6 +26,14 @@ struct Cli {
     #[clap(long, default_value = "./.spotify_token_cache.json")]
     spotify_token_cache_path: String,
 
+    /// Override which backend is used to control system volume.
+    #[clap(long, arg_enum)]
+    volume_backend: Option<controllers::volume::Backend>,
+
+    /// Override which output device (name or ID) is used for volume control.
+    #[clap(long)]
+    audio_device: Option<String>,
+
     #[clap(subcommand)]
     cmd: Command,
 }
@@ -46,28 +43,109 @@ struct Cli {
 enum Command {
     /// Transfer audio playback + settings between two computers.
     Transfer {
-        /// Which computer to transfer audio to.
-        #[clap(possible_values = ["local", "remote"])]
-        target: SyncAudioTo,
+        /// Which computer to transfer audio to. `toggle` transfers audio to
+        /// whichever computer it isn't currently playing on.
+        #[clap(possible_values = ["local", "remote", "toggle"])]
+        target: transfer::TransferTarget,
 
         /// Transfer spotify playback.
         #[clap(long)]
         spotify: bool,
 
-        /// Sync volume between both computers.
+        /// Sync volume (and mute state) between both computers.
         #[clap(long)]
         sync_volume: bool,
+
+        /// Prompt to log in to spotify if needed, instead of failing.
+        #[clap(long)]
+        interactive: bool,
+
+        /// What to do if nothing is playing on spotify (overrides the
+        /// `when_idle` config).
+        #[clap(long, arg_enum)]
+        when_idle: Option<controllers::spotify::WhenIdle>,
+    },
+    /// Watch for the USB device in the `usb_trigger` config being connected /
+    /// disconnected, and transfer audio accordingly.
+    Watch,
+    /// Continuously mirror volume (and mute state) changes between both
+    /// computers, reconnecting whenever the connection drops.
+    Mirror,
+    /// Utility: print volume (and mute state) changes as they happen.
+    WatchVolume {
+        /// Watch the remote computer instead of this one.
+        #[clap(long)]
+        remote: bool,
+    },
+    /// Log in to spotify (opening a browser), and cache the resulting token for
+    /// use by other commands.
+    Login,
+    /// Move spotify secrets from plaintext files into the OS keyring.
+    Keyring {
+        #[clap(subcommand)]
+        cmd: KeyringCommand,
     },
     /// Utility: list all currently available spotify devices.
     ListSpotifyDevices,
+    /// Utility: list all audio output devices which can be used for volume
+    /// sync.
+    ListAudioDevices,
     /// Start listening for incoming audio sync events.
     AudioServer {
         /// Port to listen on.
         #[clap(long)]
         port: u16,
+
+        /// Pre-shared key clients must authenticate with. Defaults to the
+        /// `psk` in the `volume_sync` config (if any).
+        #[clap(long)]
+        psk: Option<String>,
+
+        /// Name to advertise the server as on the LAN. Defaults to the
+        /// machine's hostname.
+        #[clap(long)]
+        name: Option<String>,
+
+        /// Don't advertise the server on the LAN (via mDNS).
+        #[clap(long)]
+        no_advertise: bool,
+
+        /// PEM-encoded TLS certificate. If set, clients must connect over TLS.
+        #[clap(long, requires = "tls-key")]
+        tls_cert: Option<PathBuf>,
+
+        /// PEM-encoded private key for `--tls-cert`.
+        #[clap(long, requires = "tls-cert")]
+        tls_key: Option<PathBuf>,
+    },
+    /// Utility: list all `music-transfer` servers advertised on the LAN.
+    Discover,
+    /// Utility: generate a self-signed TLS certificate for `audio-server`, and
+    /// print its fingerprint (for use in the client's config).
+    GenTlsCert {
+        /// Where to write the (PEM-encoded) certificate.
+        #[clap(long)]
+        cert: PathBuf,
+
+        /// Where to write the (PEM-encoded) private key.
+        #[clap(long)]
+        key: PathBuf,
+
+        /// Hostname to issue the certificate for.
+        #[clap(long, default_value = "music-transfer")]
+        hostname: String,
     },
 }
 
+#[derive(Debug, Subcommand)]
+enum KeyringCommand {
+    /// Copy the `spotify_client_secret` from the config into the OS keyring.
+    MigrateSecret,
+    /// Move the cached spotify token into the OS keyring (deleting the cache
+    /// file).
+    MigrateToken,
+}
+
 /// On Windows, re-attach the console if parent process has the console.
 /// This allows to see the log output when run from the command line.
 fn attach_console() {
@@ -80,8 +158,81 @@ fn attach_console() {
     }
 }
 
+/// Connect to the remote audio server, discovering it on the LAN if the config
+/// doesn't specify a `remote_host`.
+async fn connect_remote(
+    volume_sync: &config::VolumeSync,
+) -> anyhow::Result<rpc::client::AudioClient> {
+    let config::VolumeSync {
+        remote_host,
+        remote_port,
+        remote_name,
+        psk,
+        tls_fingerprint,
+        ..
+    } = volume_sync;
+
+    let (remote_host, remote_port) = match remote_host {
+        Some(host) => {
+            let port = remote_port.ok_or_else(|| {
+                anyhow::anyhow!(r#"missing "remote_port" from "volume_sync" config"#)
+            })?;
+            (host.clone(), port)
+        }
+        None => {
+            let peer =
+                rpc::discovery::find(remote_name.as_deref(), rpc::discovery::DISCOVERY_TIMEOUT)
+                    .await?;
+            rpc::discovery::check_compatible(&peer, psk.is_some(), tls_fingerprint.is_some())?;
+            let addr = peer
+                .addrs
+                .first()
+                .ok_or_else(|| anyhow::anyhow!("{} didn't advertise any addresses", peer.name))?;
+            log::info!("found {} at {}:{}", peer.name, addr, peer.port);
+
+            let host = match addr {
+                std::net::IpAddr::V4(addr) => addr.to_string(),
+                std::net::IpAddr::V6(addr) => format!("[{}]", addr),
+            };
+            (host, peer.port)
+        }
+    };
+
+    rpc::client::AudioClient::new(
+        remote_host,
+        remote_port,
+        psk.as_deref(),
+        tls_fingerprint.as_deref(),
+    )
+    .await
+}
+
+fn print_volume_state(state: controllers::volume::VolumeState) {
+    println!(
+        "{:.0}%{}",
+        state.volume * 100.0,
+        if state.muted { " (muted)" } else { "" }
+    );
+}
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {:?}", e);
+
+        let code = if e
+            .downcast_ref::<controllers::spotify::LoginRequired>()
+            .is_some()
+        {
+            EXIT_LOGIN_REQUIRED
+        } else {
+            1
+        };
+        std::process::exit(code);
+    }
+}
+
+async fn run() -> anyhow::Result<()> {
     attach_console();
 
     env_logger::builder().parse_filters("info").init();
@@ -101,13 +252,22 @@ async fn main() -> anyhow::Result<()> {
                 serde_json::from_str::<config::Config>(&s).context("could not parse config file")?
             }
             Err(e) => {
-                if matches!(cli.cmd, Command::AudioServer { .. }) {
-                    // it's fine if the config file couldn't be read, since the audio server doesn't
+                if matches!(
+                    cli.cmd,
+                    Command::AudioServer { .. }
+                        | Command::ListAudioDevices
+                        | Command::GenTlsCert { .. }
+                        | Command::Discover
+                        | Command::WatchVolume { remote: false }
+                ) {
+                    // it's fine if the config file couldn't be read, since these commands don't
                     // need anything from it.
                     config::Config {
                         spotify_creds: None,
                         spotify_transfer: None,
                         volume_sync: None,
+                        system_audio: None,
+                        usb_trigger: None,
                     }
                 } else {
                     return Err(e);
@@ -116,117 +276,281 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
+    let system_audio = {
+        let mut system_audio = config.system_audio.unwrap_or_default();
+        if let Some(backend) = cli.volume_backend {
+            system_audio.backend = Some(backend);
+        }
+        if let Some(audio_device) = cli.audio_device {
+            system_audio.audio_device = Some(audio_device);
+        }
+        system_audio
+    };
+
     match cli.cmd {
+        Command::Login => {
+            let spotify_creds = config
+                .spotify_creds
+                .ok_or_else(|| anyhow::anyhow!(r#"missing "spotify_creds" from config"#))?;
+
+            controllers::spotify::SpotifyWrapper::login(
+                &cli.spotify_token_cache_path,
+                &spotify_creds,
+            )
+            .await?;
+
+            println!("logged in to spotify");
+        }
+        Command::Keyring { cmd } => {
+            let spotify_creds = config
+                .spotify_creds
+                .ok_or_else(|| anyhow::anyhow!(r#"missing "spotify_creds" from config"#))?;
+
+            match cmd {
+                KeyringCommand::MigrateSecret => {
+                    secrets::migrate_client_secret(&secrets::Keyring, &spotify_creds)?;
+                    println!(
+                        r#"moved client secret into the OS keyring. remove "spotify_client_secret" from the config, and set "keyring": true"#
+                    );
+                }
+                KeyringCommand::MigrateToken => {
+                    secrets::migrate_token(
+                        &secrets::Keyring,
+                        &spotify_creds.spotify_client_id,
+                        std::path::Path::new(&cli.spotify_token_cache_path),
+                    )?;
+                    println!(
+                        r#"moved spotify token into the OS keyring. set "keyring": true in the config"#
+                    );
+                }
+            }
+        }
         Command::ListSpotifyDevices => {
-            let config::SpotifyCreds {
-                spotify_client_id,
-                spotify_client_secret,
-                spotify_redirect_uri,
-            } = {
-                config
-                    .spotify_creds
-                    .ok_or_else(|| anyhow::anyhow!(r#"missing "spotify_creds" from config"#))?
-            };
+            let spotify_creds = config
+                .spotify_creds
+                .ok_or_else(|| anyhow::anyhow!(r#"missing "spotify_creds" from config"#))?;
 
             let spotify = controllers::spotify::SpotifyWrapper::new(
                 &cli.spotify_token_cache_path,
-                &spotify_client_id,
-                &spotify_client_secret,
-                &spotify_redirect_uri,
+                &spotify_creds,
             )
             .await?;
 
             println!("{:#?}", spotify.devices().await?)
         }
-        Command::AudioServer { port } => rpc::server::AudioServer::new(port).run().await?,
-        Command::Transfer {
-            target,
-            spotify,
-            sync_volume,
+        Command::ListAudioDevices => {
+            // list devices using the same backend that would otherwise be used, but don't
+            // bother trying to open the configured device (which may not even exist)
+            let audio = controllers::volume::VolumeController::new(&config::SystemAudio {
+                audio_device: None,
+                ..system_audio
+            })
+            .context("could not init system volume controller")?;
+
+            for endpoint in audio.list_endpoints()? {
+                println!(
+                    "{} {} (id: {})",
+                    if endpoint.is_default { "*" } else { " " },
+                    endpoint.name,
+                    endpoint.id
+                );
+            }
+        }
+        Command::GenTlsCert {
+            cert,
+            key,
+            hostname,
         } => {
-            if !spotify && !sync_volume {
-                log::warn!("executed 'tranfer' without including transfer option. doing nothing...")
+            let (cert_pem, key_pem) = rpc::tls::generate_cert(&hostname)?;
+            tokio::fs::write(&cert, &cert_pem)
+                .await
+                .context(format!("could not write {:?}", cert))?;
+            rpc::tls::write_private_key(&key, &key_pem)?;
+
+            let (_, fingerprint) = rpc::tls::acceptor(&cert, &key)?;
+            println!("{}", fingerprint);
+        }
+        Command::Discover => {
+            let peers = rpc::discovery::discover(rpc::discovery::DISCOVERY_TIMEOUT).await?;
+            if peers.is_empty() {
+                println!("no servers found");
             }
 
-            if sync_volume {
-                // make sure we can actually do system audio control before doing any networking
-                let audio = controllers::volume::VolumeController::new_system_default()
-                    .context("could not init system volume controller")?;
-
-                let config::VolumeSync {
-                    remote_host,
-                    remote_port,
-                } = {
-                    config
-                        .volume_sync
-                        .ok_or_else(|| anyhow::anyhow!(r#"missing "volume_sync" from config"#))?
-                };
+            for peer in peers {
+                let addrs = peer
+                    .addrs
+                    .iter()
+                    .map(|addr| addr.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                match peer.capabilities {
+                    Some(caps) => println!(
+                        "{} (port: {}, addrs: {}, protocol: v{}, psk: {}, tls: {})",
+                        peer.name, peer.port, addrs, caps.protocol_version, caps.psk, caps.tls
+                    ),
+                    None => println!("{} (port: {}, addrs: {})", peer.name, peer.port, addrs),
+                }
+            }
+        }
+        Command::AudioServer {
+            port,
+            psk,
+            name,
+            no_advertise,
+            tls_cert,
+            tls_key,
+        } => {
+            let audio = controllers::volume::VolumeController::new(&system_audio)
+                .context("failed to init system volume controller")?;
 
-                let mut client = rpc::client::AudioClient::new(remote_host, remote_port).await?;
+            let psk = psk.or_else(|| config.volume_sync.and_then(|v| v.psk));
 
-                match target {
-                    SyncAudioTo::Local => {
-                        let new_vol = client
-                            .get_remote_volume()
-                            .await
-                            .context("error communicating with remote server")?;
+            let mut server = rpc::server::AudioServer::new(port, audio);
+            if let Some(psk) = psk {
+                server = server.with_psk(psk);
+            }
+            if let (Some(cert), Some(key)) = (tls_cert, tls_key) {
+                let (tls, fingerprint) = rpc::tls::acceptor(&cert, &key)?;
+                log::info!("using TLS certificate with fingerprint {}", fingerprint);
+                server = server.with_tls(tls);
+            }
+            if !no_advertise {
+                server =
+                    server.with_advertisement(name.unwrap_or_else(rpc::discovery::machine_name));
+            }
+            server.run().await?
+        }
+        Command::Mirror => {
+            let volume_sync = config
+                .volume_sync
+                .ok_or_else(|| anyhow::anyhow!(r#"missing "volume_sync" from config"#))?;
+
+            let audio = std::sync::Arc::new(
+                controllers::volume::VolumeController::new(&system_audio)
+                    .context("could not init system volume controller")?,
+            );
+
+            loop {
+                let res = async {
+                    let client = connect_remote(&volume_sync).await?;
+                    log::info!("connected to remote server. mirroring volume...");
+                    client.mirror(audio.clone()).await
+                };
 
-                        log::info!("setting local volume to {}", new_vol);
+                match res.await {
+                    Ok(()) => log::warn!("remote server closed the connection"),
+                    Err(e) => log::error!("error while mirroring volume: {:#}", e),
+                }
 
-                        audio.set_master_volume(new_vol)?;
-                    }
-                    SyncAudioTo::Remote => {
-                        let current_volume = audio.get_master_volume()?;
+                log::info!("reconnecting in {:?}...", MIRROR_RECONNECT_DELAY);
+                tokio::time::sleep(MIRROR_RECONNECT_DELAY).await;
+            }
+        }
+        Command::WatchVolume { remote } => {
+            use tokio_stream::StreamExt;
 
-                        log::info!("setting remote volume to {}", current_volume);
+            if remote {
+                let volume_sync = config
+                    .volume_sync
+                    .ok_or_else(|| anyhow::anyhow!(r#"missing "volume_sync" from config"#))?;
 
-                        client
-                            .set_remote_volume(current_volume)
-                            .await
-                            .context("error communicating with remote server")?;
-                    }
+                let mut events = connect_remote(&volume_sync).await?.subscribe().await?;
+                while let Some(state) = events.next().await {
+                    print_volume_state(state?);
                 }
+            } else {
+                let audio = controllers::volume::VolumeController::new(&system_audio)
+                    .context("could not init system volume controller")?;
 
-                drop(client);
+                let mut events = audio.subscribe()?;
+                print_volume_state(audio.get_state()?);
+                while let Some(state) = events.next().await {
+                    print_volume_state(state);
+                }
+            }
+        }
+        Command::Transfer {
+            target,
+            spotify,
+            sync_volume,
+            interactive,
+            when_idle,
+        } => {
+            let mut spotify_transfer = config.spotify_transfer;
+            if let (Some(spotify_transfer), Some(when_idle)) = (&mut spotify_transfer, when_idle) {
+                spotify_transfer.when_idle = when_idle;
             }
 
-            if spotify {
-                let config::SpotifyCreds {
-                    spotify_client_id,
-                    spotify_client_secret,
-                    spotify_redirect_uri,
-                } = {
-                    config
-                        .spotify_creds
-                        .ok_or_else(|| anyhow::anyhow!(r#"missing "spotify_creds" from config"#))?
-                };
-
-                let config::SpotifyTransfer {
-                    spotify_name_remote,
-                    spotify_name_local,
-                } = {
-                    config
-                        .spotify_transfer
-                        .ok_or_else(|| anyhow::anyhow!(r#"missing "spotify_creds" from config"#))?
-                };
+            let mut transferer = transfer::Transferer::new(
+                system_audio,
+                config.volume_sync,
+                config.spotify_creds,
+                spotify_transfer,
+                cli.spotify_token_cache_path,
+            );
+            if interactive {
+                transferer = transferer.with_interactive_login();
+            }
 
-                let mut spotify = controllers::spotify::SpotifyWrapper::new(
-                    &cli.spotify_token_cache_path,
-                    &spotify_client_id,
-                    &spotify_client_secret,
-                    &spotify_redirect_uri,
-                )
-                .await?;
-
-                spotify
-                    .transfer_playback(
-                        match target {
-                            SyncAudioTo::Local => &spotify_name_local,
-                            SyncAudioTo::Remote => &spotify_name_remote,
-                        },
-                        sync_volume,
-                    )
-                    .await?
+            let target = match target {
+                transfer::TransferTarget::To(target) => target,
+                transfer::TransferTarget::Toggle => {
+                    let target = transferer.toggle_target(spotify, sync_volume).await?;
+                    log::info!("toggling audio over to the {} computer", target);
+                    target
+                }
+            };
+            transferer.transfer(target, spotify, sync_volume).await?
+        }
+        Command::Watch => {
+            use tokio_stream::StreamExt;
+
+            let config::UsbTrigger {
+                usb_device,
+                on_connect,
+                on_disconnect,
+                spotify,
+                sync_volume,
+            } = config
+                .usb_trigger
+                .ok_or_else(|| anyhow::anyhow!(r#"missing "usb_trigger" from config"#))?;
+            let usb_device = usb_device
+                .parse::<controllers::usb::UsbId>()
+                .context(r#"invalid "usb_device" in "usb_trigger" config"#)?;
+
+            let mut transferer = transfer::Transferer::new(
+                system_audio,
+                config.volume_sync,
+                config.spotify_creds,
+                config.spotify_transfer,
+                cli.spotify_token_cache_path,
+            );
+            transferer.warm_up(spotify, sync_volume).await?;
+
+            let mut events = controllers::usb::watch(usb_device)?;
+            log::info!("watching for USB device {}...", usb_device);
+
+            let mut keep_alive = tokio::time::interval(transfer::KEEPALIVE_INTERVAL);
+            loop {
+                tokio::select! {
+                    event = events.next() => {
+                        let event = event
+                            .ok_or_else(|| anyhow::anyhow!("stopped watching for USB devices"))?;
+                        log::info!("USB device {} event: {:?}", usb_device, event);
+
+                        let target = match event {
+                            controllers::usb::UsbEvent::Connected => on_connect,
+                            controllers::usb::UsbEvent::Disconnected => on_disconnect,
+                        };
+                        if let Some(target) = target {
+                            let res = transferer.transfer(target, spotify, sync_volume).await;
+                            if let Err(e) = res {
+                                log::error!("transfer failed: {:#}", e);
+                            }
+                        }
+                    }
+                    _ = keep_alive.tick() => transferer.keep_alive().await,
+                }
             }
         }
     };
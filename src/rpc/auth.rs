@@ -0,0 +1,150 @@
+//! Pre-shared key authentication, performed right after the protocol
+//! handshake (from protocol version 2 onwards).
+//!
+//! Both sides prove knowledge of the key without ever sending it over the
+//! wire:
+//!
+//! 1. server -> client: [`Challenge`], containing a random nonce (or nothing,
+//!    if the server doesn't require authentication)
+//! 2. client -> server: [`Proof`], containing the client's own random nonce,
+//!    and `HMAC-SHA256(key, "client" || server_nonce || client_nonce)`
+//! 3. server -> client: [`Verdict`], containing either `HMAC-SHA256(key,
+//!    "server" || client_nonce || server_nonce)`, or the reason the client was
+//!    rejected.
+//!
+//! The client checks the server's MAC too, so a client never talks to a server
+//! which doesn't know the key.
+
+use super::protocol;
+use anyhow::anyhow;
+use hmac::Hmac;
+use hmac::Mac;
+use rand::RngCore;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+
+type HmacSha256 = Hmac<Sha256>;
+
+type Nonce = [u8; 32];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Challenge {
+    /// `None` if the server doesn't require authentication
+    nonce: Option<Nonce>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Proof {
+    nonce: Nonce,
+    mac: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Verdict {
+    Accepted { mac: Vec<u8> },
+    Rejected(String),
+}
+
+fn new_nonce() -> Nonce {
+    let mut nonce = [0; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+fn mac(psk: &str, role: &[u8], first: &Nonce, second: &Nonce) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(psk.as_bytes()).expect("HMAC accepts any key size");
+    mac.update(role);
+    mac.update(first);
+    mac.update(second);
+    mac
+}
+
+/// Perform the server side of authentication. If `psk` is `None`, any client
+/// is accepted.
+pub async fn server_auth<S>(stream: &mut S, psk: Option<&str>) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let psk = match psk {
+        Some(psk) => psk,
+        None => return protocol::write_frame(stream, &Challenge { nonce: None }).await,
+    };
+
+    let server_nonce = new_nonce();
+    protocol::write_frame(
+        stream,
+        &Challenge {
+            nonce: Some(server_nonce),
+        },
+    )
+    .await?;
+
+    let proof: Proof = protocol::read_frame(stream)
+        .await?
+        .ok_or_else(|| anyhow!("client closed the connection during authentication"))?;
+
+    if mac(psk, b"client", &server_nonce, &proof.nonce)
+        .verify_slice(&proof.mac)
+        .is_err()
+    {
+        let verdict = Verdict::Rejected("invalid pre-shared key".into());
+        protocol::write_frame(stream, &verdict).await?;
+        return Err(anyhow!(
+            "client failed to authenticate (invalid pre-shared key)"
+        ));
+    }
+
+    let verdict = Verdict::Accepted {
+        mac: mac(psk, b"server", &proof.nonce, &server_nonce)
+            .finalize()
+            .into_bytes()
+            .to_vec(),
+    };
+    protocol::write_frame(stream, &verdict).await
+}
+
+/// Perform the client side of authentication. If `psk` is `None`, the server
+/// must not require authentication.
+pub async fn client_auth<S>(stream: &mut S, psk: Option<&str>) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let challenge: Challenge = protocol::read_frame(stream)
+        .await?
+        .ok_or_else(|| anyhow!("server closed the connection during authentication"))?;
+
+    let (psk, server_nonce) = match (psk, challenge.nonce) {
+        (None, None) => return Ok(()),
+        (Some(psk), Some(nonce)) => (psk, nonce),
+        (None, Some(_)) => return Err(anyhow!("remote server requires a pre-shared key")),
+        (Some(_), None) => {
+            return Err(anyhow!(
+                "remote server isn't configured with a pre-shared key, refusing to talk to it"
+            ))
+        }
+    };
+
+    let client_nonce = new_nonce();
+    let proof = Proof {
+        nonce: client_nonce,
+        mac: mac(psk, b"client", &server_nonce, &client_nonce)
+            .finalize()
+            .into_bytes()
+            .to_vec(),
+    };
+    protocol::write_frame(stream, &proof).await?;
+
+    let verdict: Verdict = protocol::read_frame(stream)
+        .await?
+        .ok_or_else(|| anyhow!("server closed the connection during authentication"))?;
+
+    match verdict {
+        Verdict::Rejected(reason) => Err(anyhow!("remote server rejected us: {}", reason)),
+        Verdict::Accepted { mac: server_mac } => mac(psk, b"server", &client_nonce, &server_nonce)
+            .verify_slice(&server_mac)
+            .map_err(|_| anyhow!("remote server failed to prove it knows the pre-shared key")),
+    }
+}
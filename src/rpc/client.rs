@@ -1,15 +1,38 @@
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
+use super::auth;
+use super::mirror;
+use super::protocol;
+use super::protocol::Event;
+use super::protocol::Request;
+use super::protocol::Response;
+use super::tls;
+use super::Stream;
+use crate::controllers::volume::VolumeController;
+use crate::controllers::volume::VolumeState;
+use anyhow::Context;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
 use tokio::net::TcpStream;
-
-// I don't want to hear a _word_ about this current protocol. it works fiiiiine
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
 pub struct AudioClient {
-    socket: TcpStream,
+    socket: Box<dyn Stream>,
+    version: u16,
 }
 
 impl AudioClient {
-    pub async fn new(remote_host: String, remote_port: u16) -> anyhow::Result<AudioClient> {
+    /// Connect to a remote server, authenticating with the given pre-shared
+    /// key (if any).
+    ///
+    /// If `tls_fingerprint` is provided, the connection is made over TLS, and
+    /// the server must present a certificate with that (SHA-256) fingerprint.
+    pub async fn new(
+        remote_host: String,
+        remote_port: u16,
+        psk: Option<&str>,
+        tls_fingerprint: Option<&str>,
+    ) -> anyhow::Result<AudioClient> {
         let remote_addr = format!("{}:{}", remote_host, remote_port);
 
         // for some reason, passing a `foo.local` address directly to TcpStream::connect
@@ -28,31 +51,218 @@ impl AudioClient {
             TcpStream::connect(remote_addr).await?
         };
 
-        Ok(AudioClient { socket })
+        let mut socket: Box<dyn Stream> = match tls_fingerprint {
+            Some(fingerprint) => {
+                // the server's name doesn't matter, since we're pinning its certificate
+                let server_name = rustls::pki_types::ServerName::try_from(remote_host)
+                    .unwrap_or_else(|_| "music-transfer".try_into().unwrap());
+                Box::new(
+                    tls::connector(fingerprint)?
+                        .connect(server_name, socket)
+                        .await
+                        .context("TLS handshake failed")?,
+                )
+            }
+            None => Box::new(socket),
+        };
+
+        let version = protocol::client_handshake(&mut socket).await?;
+        log::debug!("connected to server using protocol version {}", version);
+
+        if version >= protocol::AUTH_PROTOCOL_VERSION {
+            auth::client_auth(&mut socket, psk).await?;
+        } else if psk.is_some() {
+            return Err(anyhow::anyhow!(
+                "remote server is too old to support authentication"
+            ));
+        }
+
+        Ok(AudioClient { socket, version })
     }
 
-    pub async fn set_remote_volume(&mut self, vol: f32) -> anyhow::Result<()> {
-        self.socket
-            .write_all(format!("s:{}", vol).as_bytes())
-            .await?;
+    async fn request(&mut self, req: Request) -> anyhow::Result<Response> {
+        protocol::write_frame(&mut self.socket, &req).await?;
+        match protocol::read_frame(&mut self.socket).await? {
+            Some(Response::Error(e)) => Err(anyhow::anyhow!("remote server error: {}", e)),
+            Some(resp) => Ok(resp),
+            None => Err(anyhow::anyhow!("server unexpectedly closed the connection")),
+        }
+    }
 
-        Ok(())
+    pub async fn set_remote_volume(&mut self, vol: f32) -> anyhow::Result<()> {
+        match self.request(Request::SetVolume(vol)).await? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
     }
 
     pub async fn get_remote_volume(&mut self) -> anyhow::Result<f32> {
-        self.socket.write_all("g:".as_bytes()).await?;
+        match self.request(Request::GetVolume).await? {
+            Response::Volume(vol) => Ok(vol),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    pub async fn set_remote_mute(&mut self, muted: bool) -> anyhow::Result<()> {
+        match self.request(Request::SetMute(muted)).await? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    pub async fn get_remote_mute(&mut self) -> anyhow::Result<bool> {
+        match self.request(Request::GetMute).await? {
+            Response::Mute(muted) => Ok(muted),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Returns `false` if the app isn't playing audio on the remote.
+    pub async fn set_remote_app_volume(&mut self, app: &str, vol: f32) -> anyhow::Result<bool> {
+        match self
+            .request(Request::SetAppVolume(app.to_string(), vol))
+            .await?
+        {
+            Response::AppVolumeSet(found) => Ok(found),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Switch the remote's default output device (by name or ID).
+    pub async fn set_remote_output_device(&mut self, device: &str) -> anyhow::Result<()> {
+        match self
+            .request(Request::SetOutputDevice(device.to_string()))
+            .await?
+        {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Returns `None` if the app isn't playing audio on the remote.
+    pub async fn get_remote_app_volume(&mut self, app: &str) -> anyhow::Result<Option<f32>> {
+        match self.request(Request::GetAppVolume(app.to_string())).await? {
+            Response::AppVolume(vol) => Ok(vol),
+            resp => Err(unexpected(resp)),
+        }
+    }
 
-        let mut cmd: [u8; 2] = [0; 2];
-        self.socket.read_exact(&mut cmd).await?;
+    /// Check that the connection is still alive, keeping it from idling out.
+    pub async fn ping(&mut self) -> anyhow::Result<()> {
+        // older servers don't know about pings, so send them something harmless
+        if self.version < protocol::PING_PROTOCOL_VERSION {
+            return self.get_remote_mute().await.map(drop);
+        }
 
-        if cmd != *b"G:" {
-            return Err(anyhow::anyhow!("malformed response"));
+        match self.request(Request::Ping).await? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
         }
+    }
 
-        let mut new_vol = String::new();
-        self.socket.read_to_string(&mut new_vol).await?;
-        let new_vol = new_vol.parse::<f32>()?;
+    /// Launch the spotify client on the remote (if it isn't running already).
+    pub async fn wake_remote_spotify(&mut self) -> anyhow::Result<()> {
+        if self.version < protocol::WAKE_SPOTIFY_PROTOCOL_VERSION {
+            return Err(anyhow::anyhow!(
+                "remote server is too old to support waking spotify"
+            ));
+        }
 
-        Ok(new_vol)
+        match self.request(Request::WakeSpotify).await? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
     }
+
+    /// Mirror volume changes to / from the remote, until the connection is
+    /// closed. The remote starts off matching the local state.
+    pub async fn mirror(mut self, audio: Arc<VolumeController>) -> anyhow::Result<()> {
+        if self.version < protocol::MIRROR_PROTOCOL_VERSION {
+            return Err(anyhow::anyhow!(
+                "remote server is too old to support mirroring"
+            ));
+        }
+
+        let state = {
+            let audio = audio.clone();
+            tokio::task::spawn_blocking(move || audio.get_state()).await??
+        };
+        match self.request(Request::Mirror(state)).await? {
+            Response::Ok => {}
+            resp => return Err(unexpected(resp)),
+        }
+
+        mirror::run(self.socket, audio, None).await
+    }
+
+    /// Subscribe to the remote's volume / mute state changes. The first item
+    /// is the remote's current state.
+    ///
+    /// The stream ends with an error if the connection is lost.
+    pub async fn subscribe(mut self) -> anyhow::Result<RemoteVolumeEvents> {
+        if self.version < protocol::SUBSCRIBE_PROTOCOL_VERSION {
+            return Err(anyhow::anyhow!(
+                "remote server is too old to support subscriptions"
+            ));
+        }
+
+        match self.request(Request::Subscribe).await? {
+            Response::Ok => {}
+            resp => return Err(unexpected(resp)),
+        }
+
+        let (tx, events) = mpsc::unbounded_channel();
+        let mut socket = self.socket;
+        let reader = tokio::spawn(async move {
+            let res = loop {
+                let event =
+                    tokio::time::timeout(protocol::PEER_TIMEOUT, protocol::read_frame(&mut socket))
+                        .await;
+                match event {
+                    Ok(Ok(Some(Event::Volume(state)))) => {
+                        if tx.send(Ok(state)).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(Ok(Some(Event::Ping))) => {}
+                    Ok(Ok(None)) => {
+                        break Err(anyhow::anyhow!("server unexpectedly closed the connection"))
+                    }
+                    Ok(Err(e)) => break Err(e),
+                    Err(_) => break Err(anyhow::anyhow!("timed out waiting to hear from server")),
+                }
+            };
+            let _ = tx.send(res);
+        });
+
+        Ok(RemoteVolumeEvents { events, reader })
+    }
+}
+
+/// Async stream of a remote's volume / mute state changes, returned by
+/// [`AudioClient::subscribe`].
+pub struct RemoteVolumeEvents {
+    events: mpsc::UnboundedReceiver<anyhow::Result<VolumeState>>,
+    reader: JoinHandle<()>,
+}
+
+impl tokio_stream::Stream for RemoteVolumeEvents {
+    type Item = anyhow::Result<VolumeState>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl Drop for RemoteVolumeEvents {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+fn unexpected(resp: Response) -> anyhow::Error {
+    anyhow::anyhow!("unexpected response from server: {:?}", resp)
 }
@@ -0,0 +1,352 @@
+//! Advertise / discover `music-transfer` servers on the LAN using mDNS
+//! (DNS-SD).
+
+use super::protocol;
+use anyhow::anyhow;
+use mdns_sd::ServiceDaemon;
+use mdns_sd::ServiceEvent;
+use mdns_sd::ServiceInfo;
+use std::net::IpAddr;
+use std::time::Duration;
+
+const SERVICE_TYPE: &str = "_music-transfer._tcp.local.";
+
+/// How long to listen for servers when discovering peers.
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What a server is capable of / requires from its clients.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    /// Protocol version spoken by the server
+    pub protocol_version: u16,
+    /// Whether clients must authenticate using a pre-shared key
+    pub psk: bool,
+    /// Whether clients must connect over TLS
+    pub tls: bool,
+}
+
+/// A server found on the LAN.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    /// The name the server is advertised under (usually its machine name)
+    pub name: String,
+    pub addrs: Vec<IpAddr>,
+    pub port: u16,
+    /// `None` if the server didn't advertise its capabilities
+    pub capabilities: Option<Capabilities>,
+}
+
+/// The name this machine advertises itself under.
+pub fn machine_name() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// Keeps a service advertisement alive until dropped.
+pub struct Advertisement {
+    mdns: ServiceDaemon,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let _ = self.mdns.shutdown();
+    }
+}
+
+/// Advertise a server (running on `port`) on the LAN.
+pub fn advertise(name: &str, port: u16, caps: &Capabilities) -> anyhow::Result<Advertisement> {
+    let mdns = ServiceDaemon::new()?;
+
+    let hostname = format!("{}.local.", machine_name());
+    let properties = [
+        ("name", name.to_string()),
+        ("proto", caps.protocol_version.to_string()),
+        ("psk", yes_no(caps.psk).to_string()),
+        ("tls", yes_no(caps.tls).to_string()),
+    ];
+    let service = ServiceInfo::new(SERVICE_TYPE, name, &hostname, (), port, &properties[..])?
+        .enable_addr_auto();
+    mdns.register(service)?;
+
+    log::info!("advertising `music-transfer` server as {:?}", name);
+    Ok(Advertisement { mdns })
+}
+
+fn yes_no(b: bool) -> &'static str {
+    if b {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+fn parse_peer(info: &ServiceInfo) -> Peer {
+    let name = match info.get_property_val_str("name") {
+        Some(name) => name.to_string(),
+        None => info
+            .get_fullname()
+            .trim_end_matches(SERVICE_TYPE)
+            .trim_end_matches('.')
+            .to_string(),
+    };
+
+    let capabilities = (|| {
+        Some(Capabilities {
+            protocol_version: info.get_property_val_str("proto")?.parse().ok()?,
+            psk: info.get_property_val_str("psk")? == "yes",
+            tls: info.get_property_val_str("tls")? == "yes",
+        })
+    })();
+
+    // prefer ipv4 addresses, since `.local` resolution of ipv6 addresses tends to
+    // be slow / flaky (particularly on windows)
+    let mut addrs = info.get_addresses().iter().copied().collect::<Vec<_>>();
+    addrs.sort_by_key(|addr| (addr.is_ipv6(), *addr));
+
+    Peer {
+        name,
+        addrs,
+        port: info.get_port(),
+        capabilities,
+    }
+}
+
+/// Listen for servers on the LAN, calling `found` on each one as it's
+/// discovered. Stops once `timeout` has elapsed, or if `found` returns
+/// `false`.
+async fn browse(timeout: Duration, mut found: impl FnMut(Peer) -> bool) -> anyhow::Result<()> {
+    let mdns = ServiceDaemon::new()?;
+    let events = mdns.browse(SERVICE_TYPE)?;
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    while let Ok(event) = tokio::time::timeout_at(deadline, events.recv_async()).await {
+        if let ServiceEvent::ServiceResolved(info) = event? {
+            if !found(parse_peer(&info)) {
+                break;
+            }
+        }
+    }
+
+    let _ = mdns.shutdown();
+    Ok(())
+}
+
+/// Return all servers found on the LAN within `timeout`.
+pub async fn discover(timeout: Duration) -> anyhow::Result<Vec<Peer>> {
+    let mut peers: Vec<Peer> = Vec::new();
+    browse(timeout, |peer| {
+        // the same server may be resolved multiple times (e.g: once per network
+        // interface)
+        match peers.iter_mut().find(|p| p.name == peer.name) {
+            Some(existing) => *existing = peer,
+            None => peers.push(peer),
+        }
+        true
+    })
+    .await?;
+
+    peers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(peers)
+}
+
+/// Find a server on the LAN by (case-insensitive) name. If `name` is `None`,
+/// there must be exactly one server on the LAN.
+pub async fn find(name: Option<&str>, timeout: Duration) -> anyhow::Result<Peer> {
+    match name {
+        Some(name) => {
+            let mut peer = None;
+            browse(timeout, |p| {
+                if p.name.eq_ignore_ascii_case(name) {
+                    peer = Some(p);
+                    false
+                } else {
+                    true
+                }
+            })
+            .await?;
+            peer.ok_or_else(|| anyhow!("could not find a server named {:?} on the LAN", name))
+        }
+        None => only_peer(discover(timeout).await?),
+    }
+}
+
+/// Return the one and only server in `peers`.
+fn only_peer(mut peers: Vec<Peer>) -> anyhow::Result<Peer> {
+    match peers.len() {
+        0 => Err(anyhow!("could not find any servers on the LAN")),
+        1 => Ok(peers.remove(0)),
+        _ => Err(anyhow!(
+            "found multiple servers on the LAN ({}). please specify which one to use",
+            peers
+                .iter()
+                .map(|p| p.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// Check that `peer` is a server we can talk to, given whether or not we're
+/// configured with a pre-shared key and TLS fingerprint.
+pub fn check_compatible(peer: &Peer, psk: bool, tls: bool) -> anyhow::Result<()> {
+    let caps = match &peer.capabilities {
+        Some(caps) => caps,
+        None => return Ok(()),
+    };
+
+    if caps.protocol_version < protocol::MIN_PROTOCOL_VERSION {
+        return Err(anyhow!(
+            "{} only speaks protocol version {}",
+            peer.name,
+            caps.protocol_version
+        ));
+    }
+    if caps.psk && !psk {
+        return Err(anyhow!("{} requires a pre-shared key", peer.name));
+    }
+    if caps.tls && !tls {
+        return Err(anyhow!("{} requires TLS", peer.name));
+    }
+    // the other way around is just as fatal: the server won't answer the auth
+    // challenge, and won't do a TLS handshake
+    if psk && !caps.psk {
+        return Err(anyhow!(
+            "{} doesn't use a pre-shared key, but one is configured",
+            peer.name
+        ));
+    }
+    if tls && !caps.tls {
+        return Err(anyhow!(
+            "{} doesn't use TLS, but a TLS fingerprint is configured",
+            peer.name
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(name: &str, addrs: &str, properties: &[(&str, &str)]) -> ServiceInfo {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            name,
+            "some-host.local.",
+            addrs,
+            1234,
+            properties,
+        )
+        .unwrap()
+    }
+
+    fn peer(name: &str, capabilities: Option<Capabilities>) -> Peer {
+        Peer {
+            name: name.into(),
+            addrs: Vec::new(),
+            port: 1234,
+            capabilities,
+        }
+    }
+
+    fn caps(psk: bool, tls: bool) -> Option<Capabilities> {
+        Some(Capabilities {
+            protocol_version: protocol::PROTOCOL_VERSION,
+            psk,
+            tls,
+        })
+    }
+
+    #[test]
+    fn parses_advertised_capabilities() {
+        let info = service(
+            "desktop",
+            "fe80::1,192.168.1.20",
+            &[
+                ("name", "Desktop"),
+                ("proto", "6"),
+                ("psk", "yes"),
+                ("tls", "no"),
+            ],
+        );
+        let peer = parse_peer(&info);
+
+        assert_eq!(peer.name, "Desktop");
+        assert_eq!(peer.port, 1234);
+        // ipv4 first
+        assert_eq!(
+            peer.addrs,
+            vec![
+                "192.168.1.20".parse::<IpAddr>().unwrap(),
+                "fe80::1".parse::<IpAddr>().unwrap()
+            ]
+        );
+        let caps = peer.capabilities.unwrap();
+        assert_eq!(caps.protocol_version, 6);
+        assert!(caps.psk);
+        assert!(!caps.tls);
+    }
+
+    #[test]
+    fn parses_peers_without_capabilities() {
+        // e.g: servers which predate capabilities being advertised
+        let peer = parse_peer(&service("desktop", "192.168.1.20", &[]));
+        assert_eq!(peer.name, "desktop");
+        assert!(peer.capabilities.is_none());
+
+        let peer = parse_peer(&service(
+            "desktop",
+            "192.168.1.20",
+            &[("proto", "not-a-number"), ("psk", "no"), ("tls", "no")],
+        ));
+        assert!(peer.capabilities.is_none());
+
+        let peer = parse_peer(&service("desktop", "192.168.1.20", &[("proto", "6")]));
+        assert!(peer.capabilities.is_none());
+    }
+
+    #[test]
+    fn only_peer_requires_exactly_one_server() {
+        assert!(only_peer(Vec::new()).is_err());
+        assert_eq!(only_peer(vec![peer("a", None)]).unwrap().name, "a");
+
+        let err = only_peer(vec![peer("a", None), peer("b", None)]).unwrap_err();
+        assert!(err.to_string().contains("a, b"), "{}", err);
+    }
+
+    #[test]
+    fn check_compatible_mismatches() {
+        // old servers don't say what they need, so just give them a shot
+        assert!(check_compatible(&peer("a", None), false, false).is_ok());
+
+        assert!(check_compatible(&peer("a", caps(false, false)), false, false).is_ok());
+        assert!(check_compatible(&peer("a", caps(true, false)), true, false).is_ok());
+        assert!(check_compatible(&peer("a", caps(false, true)), false, true).is_ok());
+        assert!(check_compatible(&peer("a", caps(true, true)), true, true).is_ok());
+
+        let err = check_compatible(&peer("a", caps(true, false)), false, false).unwrap_err();
+        assert!(
+            err.to_string().contains("requires a pre-shared key"),
+            "{}",
+            err
+        );
+        let err = check_compatible(&peer("a", caps(false, true)), false, false).unwrap_err();
+        assert!(err.to_string().contains("requires TLS"), "{}", err);
+
+        // configuring a psk / fingerprint the server doesn't use is just as bad
+        let err = check_compatible(&peer("a", caps(false, false)), true, false).unwrap_err();
+        assert!(
+            err.to_string().contains("doesn't use a pre-shared key"),
+            "{}",
+            err
+        );
+        let err = check_compatible(&peer("a", caps(false, false)), false, true).unwrap_err();
+        assert!(err.to_string().contains("doesn't use TLS"), "{}", err);
+
+        let too_old = Some(Capabilities {
+            protocol_version: protocol::MIN_PROTOCOL_VERSION - 1,
+            psk: false,
+            tls: false,
+        });
+        assert!(check_compatible(&peer("a", too_old), false, false).is_err());
+    }
+}
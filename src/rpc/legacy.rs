@@ -0,0 +1,136 @@
+//! Compatibility shim for clients which predate the framed protocol in
+//! [`super::protocol`].
+
+use super::protocol;
+use super::server::run_blocking;
+use super::server::Shared;
+use anyhow::Context;
+use std::sync::Arc;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+
+// I don't want to hear a _word_ about this old protocol. it worked fiiiiine
+
+/// Check if the first two bytes sent by a client look like a legacy command
+/// (e.g: `s:`, `g:`).
+pub fn is_legacy_command(prefix: [u8; 2]) -> bool {
+    prefix[1] == b':'
+}
+
+/// Read the rest of the connection as the command's argument (legacy clients
+/// signal the end of it by shutting down their half of the connection).
+async fn read_arg<S>(socket: &mut S) -> anyhow::Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut arg = String::new();
+    socket
+        .take(protocol::MAX_FRAME_LEN as u64)
+        .read_to_string(&mut arg)
+        .await?;
+    Ok(arg)
+}
+
+/// Handle a single legacy command, where `cmd` is the command byte. Legacy
+/// clients send a single command per connection.
+pub async fn handle<S>(cmd: u8, socket: &mut S, shared: &Arc<Shared>) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    log::info!("incoming legacy cmd: {}", cmd as char);
+
+    match cmd {
+        b's' => {
+            let new_vol = read_arg(socket).await?;
+
+            log::info!("setting volume to: {}", new_vol);
+
+            let new_vol = new_vol
+                .parse::<f32>()
+                .context("invalid volume sent from client")?;
+
+            run_blocking(shared, move |audio, power| {
+                audio.set_master_volume(new_vol)?;
+                power.wake_screen()
+            })
+            .await?;
+        }
+        b'g' => {
+            let current_volume = run_blocking(shared, |audio, _| audio.get_master_volume()).await?;
+            log::info!("returning current volume: {}", current_volume);
+
+            socket
+                .write_all(format!("G:{}", current_volume).as_bytes())
+                .await?;
+        }
+        b'm' => {
+            let muted = read_arg(socket).await?;
+
+            log::info!("setting mute to: {}", muted);
+
+            let muted = muted
+                .parse::<u8>()
+                .context("invalid mute state sent from client")?
+                != 0;
+
+            run_blocking(shared, move |audio, _| audio.set_mute(muted)).await?;
+        }
+        b'n' => {
+            let muted = run_blocking(shared, |audio, _| audio.get_mute()).await?;
+            log::info!("returning current mute state: {}", muted);
+
+            socket
+                .write_all(format!("N:{}", muted as u8).as_bytes())
+                .await?;
+        }
+        b'o' => {
+            let device = read_arg(socket).await?;
+
+            log::info!("switching default output device to: {}", device);
+
+            run_blocking(shared, move |audio, _| audio.set_default_endpoint(&device)).await?;
+        }
+        b'a' => {
+            let app = read_arg(socket).await?;
+
+            let vol = {
+                let app = app.clone();
+                run_blocking(shared, move |audio, _| audio.get_app_volume(&app)).await?
+            };
+            log::info!("returning current volume of {}: {:?}", app, vol);
+
+            let vol = vol.map(|vol| vol.to_string()).unwrap_or_default();
+            socket.write_all(format!("A:{}", vol).as_bytes()).await?;
+        }
+        b'p' => {
+            let args = read_arg(socket).await?;
+
+            let (app, new_vol) = args
+                .rsplit_once('=')
+                .context("invalid app volume sent from client")?;
+
+            log::info!("setting volume of {} to: {}", app, new_vol);
+
+            let new_vol = new_vol
+                .parse::<f32>()
+                .context("invalid volume sent from client")?;
+
+            let found = {
+                let app = app.to_string();
+                run_blocking(shared, move |audio, _| audio.set_app_volume(&app, new_vol)).await?
+            };
+            if !found {
+                log::info!("{} isn't currently playing audio", app);
+            }
+
+            socket
+                .write_all(format!("P:{}", found as u8).as_bytes())
+                .await?;
+        }
+        _ => return Err(anyhow::anyhow!("invalid command")),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,178 @@
+//! Live, bidirectional volume mirroring.
+//!
+//! Once a connection has been upgraded to mirror mode (via
+//! [`Request::Mirror`](super::protocol::Request::Mirror)), both sides send each
+//! other their volume / mute state whenever it changes locally, and apply
+//! whatever state the other side sends over.
+//!
+//! Applying the other side's state triggers a local change notification, which
+//! would then get sent right back (and so on, forever). To avoid that, local
+//! changes which match a state recently applied on behalf of the other side
+//! are treated as echoes, and dropped.
+
+use super::protocol;
+use super::protocol::PEER_TIMEOUT;
+use super::protocol::PING_INTERVAL;
+use super::Stream;
+use crate::controllers::volume::VolumeController;
+use crate::controllers::volume::VolumeState;
+use anyhow::anyhow;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+
+/// How long to wait for the change notification triggered by applying the
+/// other side's state.
+const ECHO_WINDOW: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Message {
+    State(VolumeState),
+    Ping,
+}
+
+/// States which were recently applied on behalf of the other side.
+#[derive(Default)]
+struct Echoes(VecDeque<(VolumeState, Instant)>);
+
+impl Echoes {
+    fn expect(&mut self, state: VolumeState) {
+        self.0.push_back((state, Instant::now()));
+    }
+
+    /// Check if `state` is an echo of a state applied on behalf of the other
+    /// side (consuming the echo if so).
+    fn is_echo(&mut self, state: &VolumeState) -> bool {
+        self.0.retain(|(_, at)| at.elapsed() < ECHO_WINDOW);
+        match self.0.iter().position(|(s, _)| s.approx_eq(state)) {
+            Some(i) => {
+                self.0.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Apply `to` locally, expecting an echo for every change along the way.
+async fn apply(
+    audio: &Arc<VolumeController>,
+    from: VolumeState,
+    to: VolumeState,
+    echoes: &mut Echoes,
+) -> anyhow::Result<()> {
+    // volume and mute are set separately, so there's an intermediate state too
+    echoes.expect(VolumeState {
+        volume: to.volume,
+        muted: from.muted,
+    });
+    echoes.expect(to);
+
+    let audio = audio.clone();
+    tokio::task::spawn_blocking(move || {
+        audio.set_master_volume(to.volume)?;
+        audio.set_mute(to.muted)
+    })
+    .await?
+}
+
+/// Send `msg` to the other side, giving up if it stops reading.
+async fn send<W>(wr: &mut W, msg: &Message) -> anyhow::Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    tokio::time::timeout(PEER_TIMEOUT, protocol::write_frame(wr, msg))
+        .await
+        .map_err(|_| anyhow!("timed out writing to the other side"))?
+}
+
+/// Mirror volume changes to / from the other side of `stream`, until the
+/// connection is closed.
+///
+/// If `initial` is provided, it's applied locally before anything else.
+pub async fn run(
+    stream: Box<dyn Stream>,
+    audio: Arc<VolumeController>,
+    initial: Option<VolumeState>,
+) -> anyhow::Result<()> {
+    // subscribe _before_ applying the initial state, so that its echo isn't
+    // missed
+    let (mut changes, mut local) = {
+        let audio = audio.clone();
+        tokio::task::spawn_blocking(move || {
+            let changes = audio.subscribe()?;
+            Ok::<_, anyhow::Error>((changes, audio.get_state()?))
+        })
+        .await??
+    };
+
+    let mut echoes = Echoes::default();
+    if let Some(initial) = initial {
+        log::info!("mirroring initial state: {:?}", initial);
+        apply(&audio, local, initial, &mut echoes).await?;
+        local = initial;
+    }
+
+    let (mut rd, mut wr) = tokio::io::split(stream);
+
+    // reads aren't cancel-safe, so they get their own task
+    let (peer_tx, mut from_peer) = mpsc::unbounded_channel();
+    let mut reader = tokio::spawn(async move {
+        loop {
+            let msg = tokio::time::timeout(PEER_TIMEOUT, protocol::read_frame(&mut rd))
+                .await
+                .map_err(|_| anyhow!("timed out waiting to hear from the other side"))??;
+            match msg {
+                Some(msg) => {
+                    if peer_tx.send(msg).is_err() {
+                        return Ok(());
+                    }
+                }
+                None => return Ok(()),
+            }
+        }
+    });
+
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    let res = loop {
+        tokio::select! {
+            msg = from_peer.recv() => match msg {
+                Some(Message::State(state)) => {
+                    if state.approx_eq(&local) {
+                        continue;
+                    }
+                    log::info!("mirroring remote change: {:?}", state);
+                    if let Err(e) = apply(&audio, local, state, &mut echoes).await {
+                        break Err(e);
+                    }
+                    local = state;
+                }
+                Some(Message::Ping) => {}
+                None => break (&mut reader).await.map_err(Into::into).and_then(|res| res),
+            },
+            Some(state) = changes.next() => {
+                if echoes.is_echo(&state) || state.approx_eq(&local) {
+                    continue;
+                }
+                log::info!("mirroring local change: {:?}", state);
+                local = state;
+                if let Err(e) = send(&mut wr, &Message::State(state)).await {
+                    break Err(e);
+                }
+            }
+            _ = ping.tick() => {
+                if let Err(e) = send(&mut wr, &Message::Ping).await {
+                    break Err(e);
+                }
+            }
+        }
+    };
+
+    reader.abort();
+    res
+}
@@ -1,2 +1,389 @@
+mod auth;
 pub mod client;
+pub mod discovery;
+mod legacy;
+mod mirror;
+mod protocol;
 pub mod server;
+pub mod tls;
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+
+/// A bidirectional byte stream that the RPC protocol can run over (e.g: plain
+/// TCP, or TLS).
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controllers::volume::mock::MockCall;
+    use crate::controllers::volume::mock::MockVolume;
+    use crate::controllers::volume::VolumeController;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    /// Spawn a server backed by `mock`, returning the port it's listening on.
+    async fn spawn_server(mock: &MockVolume, psk: Option<&str>) -> u16 {
+        let server = server::AudioServer::new(0, VolumeController::from_backend(mock.clone()));
+        match psk {
+            Some(psk) => spawn(server.with_psk(psk.into())).await,
+            None => spawn(server).await,
+        }
+    }
+
+    async fn spawn(server: server::AudioServer) -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(server.serve(listener));
+        port
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let mock = MockVolume::new(0.5);
+        mock.add_app("Spotify.exe", 0.75);
+        let port = spawn_server(&mock, None).await;
+
+        let mut client = client::AudioClient::new("127.0.0.1".into(), port, None, None)
+            .await
+            .unwrap();
+        client.set_remote_volume(0.25).await.unwrap();
+        client.set_remote_mute(true).await.unwrap();
+        assert_eq!(client.get_remote_volume().await.unwrap(), 0.25);
+        assert!(client.get_remote_mute().await.unwrap());
+        assert_eq!(
+            client.get_remote_app_volume("spotify").await.unwrap(),
+            Some(0.75)
+        );
+        assert!(client.set_remote_app_volume("spotify", 1.0).await.unwrap());
+        assert!(!client.set_remote_app_volume("discord", 1.0).await.unwrap());
+        client.set_remote_output_device("Headphones").await.unwrap();
+
+        assert_eq!(
+            mock.calls(),
+            vec![
+                MockCall::SetMasterVolume(0.25),
+                MockCall::SetMute(true),
+                MockCall::GetMasterVolume,
+                MockCall::GetMute,
+                MockCall::GetAppVolume("spotify".into()),
+                MockCall::SetAppVolume("spotify".into(), 1.0),
+                MockCall::SetAppVolume("discord".into(), 1.0),
+                MockCall::SetDefaultEndpoint("Headphones".into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn legacy_client() {
+        let mock = MockVolume::new(0.5);
+        let port = spawn_server(&mock, None).await;
+
+        let mut socket = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        socket.write_all(b"s:0.25").await.unwrap();
+        drop(socket);
+
+        // legacy clients don't get a response to wait on, so the set races
+        // the get below
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while mock.calls().is_empty() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for the volume to be set");
+
+        let mut socket = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        socket.write_all(b"g:").await.unwrap();
+        let mut resp = String::new();
+        socket.read_to_string(&mut resp).await.unwrap();
+        assert_eq!(resp, "G:0.25");
+
+        assert_eq!(
+            mock.calls(),
+            vec![MockCall::SetMasterVolume(0.25), MockCall::GetMasterVolume]
+        );
+    }
+
+    #[tokio::test]
+    async fn survives_bad_clients() {
+        let mock = MockVolume::new(0.5);
+        let port = spawn_server(&mock, None).await;
+
+        // garbage handshake
+        let mut socket = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        socket.write_all(b"garbage!").await.unwrap();
+        drop(socket);
+
+        // garbage legacy command
+        let mut socket = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        socket.write_all(b"s:loud").await.unwrap();
+        drop(socket);
+
+        // undecodable request, followed by a valid one on the same connection
+        let mut socket = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        protocol::client_handshake(&mut socket).await.unwrap();
+        auth::client_auth(&mut socket, None).await.unwrap();
+        socket
+            .write_all(&[0, 0, 0, 4, 0xff, 0xff, 0xff, 0xff])
+            .await
+            .unwrap();
+        let resp = protocol::read_frame(&mut socket).await.unwrap();
+        assert!(matches!(resp, Some(protocol::Response::Error(_))));
+        protocol::write_frame(&mut socket, &protocol::Request::GetVolume)
+            .await
+            .unwrap();
+        let resp = protocol::read_frame(&mut socket).await.unwrap();
+        assert!(matches!(resp, Some(protocol::Response::Volume(v)) if v == 0.5));
+        drop(socket);
+
+        let mut client = client::AudioClient::new("127.0.0.1".into(), port, None, None)
+            .await
+            .unwrap();
+        client.set_remote_volume(0.25).await.unwrap();
+
+        assert_eq!(
+            mock.calls(),
+            vec![MockCall::GetMasterVolume, MockCall::SetMasterVolume(0.25)]
+        );
+    }
+
+    #[tokio::test]
+    async fn stalled_client_does_not_block_others() {
+        let mock = MockVolume::new(0.5);
+        let port = spawn_server(&mock, None).await;
+
+        // connects, but never sends anything
+        let _stalled = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+
+        let client = client::AudioClient::new("127.0.0.1".into(), port, None, None);
+        let mut client = tokio::time::timeout(std::time::Duration::from_secs(5), client)
+            .await
+            .expect("server is blocked by stalled client")
+            .unwrap();
+        assert_eq!(client.get_remote_volume().await.unwrap(), 0.5);
+    }
+
+    #[tokio::test]
+    async fn psk_auth() {
+        let mock = MockVolume::new(0.5);
+        let port = spawn_server(&mock, Some("hunter2")).await;
+
+        let mut client = client::AudioClient::new("127.0.0.1".into(), port, Some("hunter2"), None)
+            .await
+            .unwrap();
+        assert_eq!(client.get_remote_volume().await.unwrap(), 0.5);
+
+        let wrong_key =
+            client::AudioClient::new("127.0.0.1".into(), port, Some("hunter3"), None).await;
+        assert!(wrong_key.is_err());
+        let no_key = client::AudioClient::new("127.0.0.1".into(), port, None, None).await;
+        assert!(no_key.is_err());
+
+        // legacy clients can't authenticate
+        let mut socket = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        socket.write_all(b"s:1.0").await.unwrap();
+        drop(socket);
+
+        // ...and clients with a key won't talk to servers without one
+        let open_port = spawn_server(&mock, None).await;
+        let unauthenticated_server =
+            client::AudioClient::new("127.0.0.1".into(), open_port, Some("hunter2"), None).await;
+        assert!(unauthenticated_server.is_err());
+
+        assert_eq!(mock.calls(), vec![MockCall::GetMasterVolume]);
+    }
+
+    #[tokio::test]
+    async fn tls() {
+        let dir = std::env::temp_dir().join(format!("music-transfer-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let (cert_pem, key_pem) = tls::generate_cert("music-transfer").unwrap();
+        std::fs::write(&cert, cert_pem).unwrap();
+        std::fs::write(&key, key_pem).unwrap();
+        let (acceptor, fingerprint) = tls::acceptor(&cert, &key).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mock = MockVolume::new(0.5);
+        let port = spawn(
+            server::AudioServer::new(0, VolumeController::from_backend(mock.clone()))
+                .with_tls(acceptor),
+        )
+        .await;
+
+        let mut client =
+            client::AudioClient::new("127.0.0.1".into(), port, None, Some(&fingerprint))
+                .await
+                .unwrap();
+        assert_eq!(client.get_remote_volume().await.unwrap(), 0.5);
+
+        // fingerprints are case (and separator) insensitive
+        let lowercase = fingerprint.replace(':', "").to_lowercase();
+        assert!(
+            client::AudioClient::new("127.0.0.1".into(), port, None, Some(&lowercase))
+                .await
+                .is_ok()
+        );
+
+        let wrong_fingerprint = tls::fingerprint(b"some other cert");
+        let wrong_cert =
+            client::AudioClient::new("127.0.0.1".into(), port, None, Some(&wrong_fingerprint))
+                .await;
+        assert!(wrong_cert.is_err());
+
+        let plaintext = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            client::AudioClient::new("127.0.0.1".into(), port, None, None),
+        )
+        .await
+        .unwrap();
+        assert!(plaintext.is_err());
+
+        assert_eq!(mock.calls(), vec![MockCall::GetMasterVolume]);
+    }
+
+    /// Every call which changed `mock`'s state.
+    fn changes(mock: &MockVolume) -> Vec<MockCall> {
+        mock.calls()
+            .into_iter()
+            .filter(|call| matches!(call, MockCall::SetMasterVolume(_) | MockCall::SetMute(_)))
+            .collect()
+    }
+
+    async fn wait_for_changes(mock: &MockVolume, n: usize) {
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while changes(mock).len() < n {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for changes to be mirrored");
+    }
+
+    #[tokio::test]
+    async fn mirror() {
+        use crate::controllers::volume::VolumeBackend;
+
+        let remote = MockVolume::new(0.5);
+        let port = spawn_server(&remote, None).await;
+
+        let local = MockVolume::new(0.2);
+        let client = client::AudioClient::new("127.0.0.1".into(), port, None, None)
+            .await
+            .unwrap();
+        tokio::spawn(
+            client.mirror(std::sync::Arc::new(VolumeController::from_backend(
+                local.clone(),
+            ))),
+        );
+
+        // the remote starts off matching the local state...
+        wait_for_changes(&remote, 2).await;
+
+        // ...and changes on either side are mirrored to the other
+        remote.set_master_volume(0.7).unwrap();
+        wait_for_changes(&local, 2).await;
+        local.set_mute(true).unwrap();
+        wait_for_changes(&remote, 5).await;
+
+        // ...without bouncing back and forth
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(
+            changes(&remote),
+            vec![
+                MockCall::SetMasterVolume(0.2),
+                MockCall::SetMute(false),
+                MockCall::SetMasterVolume(0.7),
+                MockCall::SetMasterVolume(0.7),
+                MockCall::SetMute(true),
+            ]
+        );
+        assert_eq!(
+            changes(&local),
+            vec![
+                MockCall::SetMasterVolume(0.7),
+                MockCall::SetMute(false),
+                MockCall::SetMute(true),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn long_lived_connections_do_not_hog_slots() {
+        use tokio_stream::StreamExt;
+
+        let remote = MockVolume::new(0.5);
+        let port = spawn_server(&remote, None).await;
+
+        let mut subscriptions = Vec::new();
+        for _ in 0..server::MAX_CONNECTIONS {
+            let client = client::AudioClient::new("127.0.0.1".into(), port, None, None)
+                .await
+                .unwrap();
+            let mut events = client.subscribe().await.unwrap();
+            events.next().await.unwrap().unwrap();
+            subscriptions.push(events);
+        }
+
+        for _ in 0..server::MAX_CONNECTIONS {
+            let client = client::AudioClient::new("127.0.0.1".into(), port, None, None)
+                .await
+                .unwrap();
+            let local = VolumeController::from_backend(MockVolume::new(0.5));
+            tokio::spawn(client.mirror(std::sync::Arc::new(local)));
+        }
+        // each mirror applies its initial state once it's up and running
+        wait_for_changes(&remote, 2 * server::MAX_CONNECTIONS).await;
+
+        let client = client::AudioClient::new("127.0.0.1".into(), port, None, None);
+        let mut client = tokio::time::timeout(std::time::Duration::from_secs(5), client)
+            .await
+            .expect("server is out of connections")
+            .unwrap();
+        assert_eq!(client.get_remote_volume().await.unwrap(), 0.5);
+    }
+
+    #[tokio::test]
+    async fn subscribe() {
+        use crate::controllers::volume::VolumeBackend;
+        use crate::controllers::volume::VolumeState;
+        use tokio_stream::StreamExt;
+
+        let remote = MockVolume::new(0.5);
+        let port = spawn_server(&remote, None).await;
+
+        let client = client::AudioClient::new("127.0.0.1".into(), port, None, None)
+            .await
+            .unwrap();
+        let mut events = client.subscribe().await.unwrap();
+
+        // the current state comes first...
+        let state = |volume, muted| VolumeState { volume, muted };
+        assert_eq!(events.next().await.unwrap().unwrap(), state(0.5, false));
+
+        // ...followed by any changes
+        remote.set_master_volume(0.7).unwrap();
+        remote.set_mute(true).unwrap();
+        assert_eq!(events.next().await.unwrap().unwrap(), state(0.7, false));
+        assert_eq!(events.next().await.unwrap().unwrap(), state(0.7, true));
+    }
+}
@@ -0,0 +1,248 @@
+//! The `music-transfer` RPC protocol.
+//!
+//! A connection starts with a handshake, where the client sends [`MAGIC`]
+//! followed by the (big-endian `u16`) protocol version it speaks. The server
+//! replies in kind with the version it's willing to speak, which is the lower
+//! of the two versions.
+//!
+//! From version 2 onwards, the handshake is followed by (optional) pre-shared
+//! key authentication. See [`super::auth`] for details.
+//!
+//! After the handshake, the client sends any number of [`Request`]s, each of
+//! which gets a single [`Response`] from the server. Messages are framed using
+//! a big-endian `u32` length prefix, followed by the bincode-encoded message.
+//!
+//! If the server fails to handle a request (including requests it couldn't
+//! make sense of), it replies with [`Response::Error`], and keeps the
+//! connection open.
+//!
+//! From version 3 onwards, a connection can be upgraded to mirror mode using
+//! [`Request::Mirror`]. See [`super::mirror`] for details.
+//!
+//! From version 4 onwards, a connection can be upgraded to a subscription
+//! using [`Request::Subscribe`], after which the server sends the client an
+//! [`Event`] whenever its volume / mute state changes (starting with its
+//! current state), along with periodic [`Event::Ping`]s.
+//!
+//! From version 5 onwards, clients can send [`Request::Ping`] to keep an idle
+//! connection from timing out.
+
+use crate::controllers::volume::VolumeState;
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+
+/// Sent by both sides at the start of the handshake.
+///
+/// Notably, the second byte is _not_ a `:`, which is how the server tells new
+/// clients apart from legacy ones.
+pub const MAGIC: [u8; 4] = *b"MTRX";
+
+/// Current protocol version.
+pub const PROTOCOL_VERSION: u16 = 6;
+
+/// First protocol version to support authentication.
+pub const AUTH_PROTOCOL_VERSION: u16 = 2;
+
+/// First protocol version to support [`Request::Mirror`].
+pub const MIRROR_PROTOCOL_VERSION: u16 = 3;
+
+/// First protocol version to support [`Request::Subscribe`].
+pub const SUBSCRIBE_PROTOCOL_VERSION: u16 = 4;
+
+/// First protocol version to support [`Request::Ping`].
+pub const PING_PROTOCOL_VERSION: u16 = 5;
+
+/// First protocol version to support [`Request::WakeSpotify`].
+pub const WAKE_SPOTIFY_PROTOCOL_VERSION: u16 = 6;
+
+/// Oldest protocol version we can still speak.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Upper bound on frame size, so that a bogus length prefix can't make us
+/// allocate a ridiculous amount of memory.
+pub(super) const MAX_FRAME_LEN: u32 = 64 * 1024;
+
+/// How often long-lived connections (mirror / subscription) let the other
+/// side know they're still around.
+pub const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long to wait to hear from the other side of a long-lived connection
+/// before giving up on it.
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    GetVolume,
+    SetVolume(f32),
+    GetMute,
+    SetMute(bool),
+    GetAppVolume(String),
+    SetAppVolume(String, f32),
+    SetOutputDevice(String),
+    /// Switch the connection over to mirror mode, starting from the client's
+    /// current state. The server replies with [`Response::Ok`] before
+    /// switching.
+    Mirror(VolumeState),
+    /// Switch the connection over to a stream of [`Event`]s. The server
+    /// replies with [`Response::Ok`] before switching.
+    Subscribe,
+    /// Does nothing (other than keep the connection from idling out). The
+    /// server replies with [`Response::Ok`].
+    Ping,
+    /// Launch the spotify client (if it isn't running already).
+    WakeSpotify,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Ok,
+    Volume(f32),
+    Mute(bool),
+    /// `None` if the app isn't playing audio
+    AppVolume(Option<f32>),
+    /// `false` if the app isn't playing audio
+    AppVolumeSet(bool),
+    /// The request could not be handled
+    Error(String),
+}
+
+/// Sent by the server to subscribed clients.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Event {
+    Volume(VolumeState),
+    Ping,
+}
+
+/// Perform the client side of the handshake, returning the negotiated
+/// protocol version.
+pub async fn client_handshake<S>(stream: &mut S) -> anyhow::Result<u16>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut hello = [0; 6];
+    hello[..4].copy_from_slice(&MAGIC);
+    hello[4..].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    stream.write_all(&hello).await?;
+
+    let mut resp = [0; 6];
+    stream.read_exact(&mut resp).await?;
+    if resp[..4] != MAGIC {
+        return Err(anyhow!("remote is not a `music-transfer` server"));
+    }
+
+    let version = u16::from_be_bytes([resp[4], resp[5]]);
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(anyhow!(
+            "remote server only speaks protocol version {} (need at least {})",
+            version,
+            MIN_PROTOCOL_VERSION
+        ));
+    }
+
+    Ok(version)
+}
+
+/// Perform the server side of the handshake, returning the negotiated
+/// protocol version.
+///
+/// `prefix` contains the first two bytes sent by the client, which the server
+/// has already read to check for legacy clients.
+pub async fn server_handshake<S>(stream: &mut S, prefix: [u8; 2]) -> anyhow::Result<u16>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut hello = [0; 6];
+    hello[..2].copy_from_slice(&prefix);
+    stream.read_exact(&mut hello[2..]).await?;
+    if hello[..4] != MAGIC {
+        return Err(anyhow!("client sent an invalid handshake"));
+    }
+
+    let version = u16::from_be_bytes([hello[4], hello[5]]).min(PROTOCOL_VERSION);
+
+    let mut resp = [0; 6];
+    resp[..4].copy_from_slice(&MAGIC);
+    resp[4..].copy_from_slice(&version.to_be_bytes());
+    stream.write_all(&resp).await?;
+
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(anyhow!(
+            "client only speaks protocol version {} (need at least {})",
+            version,
+            MIN_PROTOCOL_VERSION
+        ));
+    }
+
+    Ok(version)
+}
+
+/// Write a single message.
+pub async fn write_frame<W, T>(w: &mut W, msg: &T) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let payload = bincode::serialize(msg)?;
+    if payload.len() > MAX_FRAME_LEN as usize {
+        return Err(anyhow!("message is too large to send"));
+    }
+
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend((payload.len() as u32).to_be_bytes());
+    frame.extend(payload);
+    w.write_all(&frame).await?;
+
+    Ok(())
+}
+
+/// Read a single message, returning `None` if the other side cleanly closed
+/// the connection.
+pub async fn read_frame<R, T>(r: &mut R) -> anyhow::Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    match read_raw_frame(r).await? {
+        Some(payload) => Ok(Some(decode(&payload)?)),
+        None => Ok(None),
+    }
+}
+
+/// Decode the payload of a frame.
+pub fn decode<T: DeserializeOwned>(payload: &[u8]) -> anyhow::Result<T> {
+    Ok(bincode::deserialize(payload)?)
+}
+
+/// Read a single frame's (undecoded) payload, returning `None` if the other
+/// side cleanly closed the connection.
+///
+/// Useful in cases where a payload that can't be decoded shouldn't bring down
+/// the whole connection.
+pub async fn read_raw_frame<R>(r: &mut R) -> anyhow::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len = [0; 4];
+    match r.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(anyhow!("received frame is too large ({} bytes)", len));
+    }
+
+    let mut payload = vec![0; len as usize];
+    r.read_exact(&mut payload).await?;
+
+    Ok(Some(payload))
+}
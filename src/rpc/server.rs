@@ -1,67 +1,338 @@
+use super::auth;
+use super::discovery;
+use super::legacy;
+use super::mirror;
+use super::protocol;
+use super::protocol::Event;
+use super::protocol::Request;
+use super::protocol::Response;
+use super::Stream;
+use crate::controllers::power::PowerController;
+use crate::controllers::volume::VolumeController;
 use anyhow::Context;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::StreamExt;
 
-// I don't want to hear a _word_ about this current protocol. it works fiiiiine
+/// Maximum number of connections being serviced at any one time. Once hit,
+/// new connections are left in the listen backlog until a slot frees up.
+pub(super) const MAX_CONNECTIONS: usize = 16;
+
+/// Maximum number of connections which have been switched over to mirror
+/// mode, or to a subscription. These stick around for as long as the client
+/// does, so they're capped separately (and don't count towards
+/// [`MAX_CONNECTIONS`]).
+const MAX_LONG_LIVED_CONNECTIONS: usize = 32;
+
+/// How long to wait on any single read / write before giving up on the
+/// client. This also doubles as the idle timeout between requests.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct AudioServer {
     port: u16,
+    audio: VolumeController,
+    psk: Option<String>,
+    tls: Option<TlsAcceptor>,
+    advertise_as: Option<String>,
+}
+
+/// State shared between all connections.
+pub(super) struct Shared {
+    psk: Option<String>,
+    tls: Option<TlsAcceptor>,
+    audio: Arc<VolumeController>,
+    power: PowerController,
+    long_lived: Arc<Semaphore>,
+}
+
+/// Run `f` against the volume / power backends. Backends may block (e.g:
+/// shelling out to `pactl`), so this keeps them off the async executor.
+pub(super) async fn run_blocking<T, F>(shared: &Arc<Shared>, f: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&VolumeController, &PowerController) -> anyhow::Result<T> + Send + 'static,
+{
+    let shared = shared.clone();
+    tokio::task::spawn_blocking(move || f(&shared.audio, &shared.power))
+        .await
+        .unwrap_or_else(|e| Err(anyhow::anyhow!("request handler panicked: {}", e)))
 }
 
 impl AudioServer {
-    pub fn new(port: u16) -> AudioServer {
-        AudioServer { port }
+    pub fn new(port: u16, audio: VolumeController) -> AudioServer {
+        AudioServer {
+            port,
+            audio,
+            psk: None,
+            tls: None,
+            advertise_as: None,
+        }
     }
 
-    pub async fn run(self) -> anyhow::Result<()> {
-        let audio = crate::controllers::volume::VolumeController::new_system_default()
-            .context("failed to init system volume controller")?;
-        let power = crate::controllers::power::PowerController::new_system_default()
-            .context("failed to init system power controller")?;
+    /// Advertise the server on the LAN (via mDNS) under the given name.
+    pub fn with_advertisement(mut self, name: String) -> AudioServer {
+        self.advertise_as = Some(name);
+        self
+    }
+
+    /// Only accept connections over TLS.
+    pub fn with_tls(mut self, tls: TlsAcceptor) -> AudioServer {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Require clients to authenticate using the given pre-shared key.
+    pub fn with_psk(mut self, psk: String) -> AudioServer {
+        self.psk = Some(psk);
+        self
+    }
 
+    pub async fn run(self) -> anyhow::Result<()> {
         let addr = format!("0.0.0.0:{}", self.port);
         log::info!("binding `music-transfer` server to {}", addr);
 
         let listener = TcpListener::bind(&addr).await?;
 
+        // discovery is a nicety, so don't bail if mDNS isn't working
+        let _advertisement = match &self.advertise_as {
+            Some(name) => {
+                let caps = discovery::Capabilities {
+                    protocol_version: protocol::PROTOCOL_VERSION,
+                    psk: self.psk.is_some(),
+                    tls: self.tls.is_some(),
+                };
+                discovery::advertise(name, self.port, &caps)
+                    .map_err(|e| log::warn!("could not advertise server via mDNS: {:#}", e))
+                    .ok()
+            }
+            None => None,
+        };
+
+        self.serve(listener).await
+    }
+
+    /// Serve requests from an already-bound listener.
+    ///
+    /// Connections are handled concurrently, each in its own task, so a
+    /// misbehaving client (or a request that fails / panics) only takes down
+    /// its own connection, and never the server itself.
+    pub async fn serve(self, listener: TcpListener) -> anyhow::Result<()> {
+        let shared = Arc::new(Shared {
+            power: PowerController::new_system_default()
+                .context("failed to init system power controller")?,
+            audio: Arc::new(self.audio),
+            psk: self.psk,
+            tls: self.tls,
+            long_lived: Arc::new(Semaphore::new(MAX_LONG_LIVED_CONNECTIONS)),
+        });
+        if shared.psk.is_none() {
+            log::warn!("no pre-shared key configured. anyone on the network can control this computer's volume!");
+        }
+        let slots = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+
         loop {
-            let (mut socket, addr) = listener.accept().await?;
+            let permit = match slots.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    log::warn!("too many connections! waiting for one to close...");
+                    slots.clone().acquire_owned().await?
+                }
+            };
+
+            let (socket, addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    log::error!("failed to accept connection: {}", e);
+                    continue;
+                }
+            };
             log::info!("accepted connection from {}", addr);
 
-            let mut cmd: [u8; 2] = [0; 2];
-            socket.read_exact(&mut cmd).await?;
+            // the permit is held by the handler (rather than this task), so
+            // that it can be traded in for a long-lived one
+            let conn = tokio::spawn(handle_connection(socket, shared.clone(), permit));
+            tokio::spawn(async move {
+                match conn.await {
+                    Ok(Ok(())) => log::info!("connection from {} closed", addr),
+                    Ok(Err(e)) => log::error!("error while serving {}: {:#}", addr, e),
+                    Err(e) => log::error!("connection handler for {} panicked: {}", addr, e),
+                }
+            });
+        }
+    }
+}
+
+/// Run `fut`, bailing out if it takes longer than [`IO_TIMEOUT`].
+async fn with_timeout<T>(fut: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+    tokio::time::timeout(IO_TIMEOUT, fut)
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out waiting on client"))?
+}
 
-            if cmd[1] != b':' {
-                return Err(anyhow::anyhow!("malformed command"));
-            }
+async fn handle_connection(
+    socket: TcpStream,
+    shared: Arc<Shared>,
+    permit: OwnedSemaphorePermit,
+) -> anyhow::Result<()> {
+    let mut socket: Box<dyn Stream> = match &shared.tls {
+        Some(tls) => Box::new(
+            with_timeout(async { Ok(tls.accept(socket).await?) })
+                .await
+                .context("TLS handshake failed")?,
+        ),
+        None => Box::new(socket),
+    };
 
-            log::info!("incoming cmd: {}", cmd[0] as char);
+    let mut prefix: [u8; 2] = [0; 2];
+    with_timeout(async { Ok(socket.read_exact(&mut prefix).await?) }).await?;
 
-            match cmd[0] {
-                b's' => {
-                    let mut new_vol = String::new();
-                    socket.read_to_string(&mut new_vol).await?;
+    if legacy::is_legacy_command(prefix) {
+        if shared.psk.is_some() {
+            return Err(anyhow::anyhow!(
+                "rejected legacy command (legacy clients can't authenticate)"
+            ));
+        }
+        return with_timeout(legacy::handle(prefix[0], &mut socket, &shared)).await;
+    }
 
-                    log::info!("setting volume to: {}", new_vol);
+    let version = with_timeout(protocol::server_handshake(&mut socket, prefix)).await?;
+    log::debug!("client is using protocol version {}", version);
 
-                    let new_vol = new_vol
-                        .parse::<f32>()
-                        .context("invalid volume sent from client")?;
+    if version >= protocol::AUTH_PROTOCOL_VERSION {
+        with_timeout(auth::server_auth(&mut socket, shared.psk.as_deref()))
+            .await
+            .context("rejected unauthenticated client")?;
+    } else if shared.psk.is_some() {
+        return Err(anyhow::anyhow!(
+            "rejected client (protocol version {} doesn't support authentication)",
+            version
+        ));
+    }
 
-                    audio.set_master_volume(new_vol)?;
-                    power.wake_screen()?;
+    while let Some(payload) = with_timeout(protocol::read_raw_frame(&mut socket)).await? {
+        let resp = match protocol::decode(&payload) {
+            Ok(Request::Mirror(state)) => match shared.long_lived.clone().try_acquire_owned() {
+                Ok(long_lived) => {
+                    log::info!("switching connection over to mirror mode");
+                    with_timeout(protocol::write_frame(&mut socket, &Response::Ok)).await?;
+                    drop(permit);
+                    let res = mirror::run(socket, shared.audio.clone(), Some(state)).await;
+                    drop(long_lived);
+                    return res;
                 }
-                b'g' => {
-                    let current_volume = audio.get_master_volume()?;
-                    log::info!("returning current volume: {}", current_volume);
-
-                    socket
-                        .write_all(format!("G:{}", current_volume).as_bytes())
-                        .await?;
+                Err(_) => Err(anyhow::anyhow!("too many clients are already connected")),
+            },
+            Ok(Request::Subscribe) => match shared.long_lived.clone().try_acquire_owned() {
+                Ok(long_lived) => {
+                    log::info!("switching connection over to a subscription");
+                    with_timeout(protocol::write_frame(&mut socket, &Response::Ok)).await?;
+                    drop(permit);
+                    let res = forward_events(socket, &shared).await;
+                    drop(long_lived);
+                    return res;
                 }
-                _ => return Err(anyhow::anyhow!("invalid command")),
+                Err(_) => Err(anyhow::anyhow!("too many clients are already subscribed")),
+            },
+            Ok(Request::Ping) => {
+                log::debug!("incoming ping");
+                Ok(Response::Ok)
             }
-        }
+            Ok(req) => {
+                log::info!("incoming request: {:?}", req);
+                run_blocking(&shared, move |audio, power| {
+                    handle_request(req, audio, power)
+                })
+                .await
+            }
+            Err(e) => Err(e.context("malformed request")),
+        };
+
+        let resp = resp.unwrap_or_else(|e| {
+            log::error!("failed to handle request: {:#}", e);
+            Response::Error(format!("{:#}", e))
+        });
+        with_timeout(protocol::write_frame(&mut socket, &resp)).await?;
+    }
+
+    Ok(())
+}
+
+/// Send the client its current volume / mute state, followed by any changes
+/// to it, until the client hangs up.
+async fn forward_events(socket: Box<dyn Stream>, shared: &Arc<Shared>) -> anyhow::Result<()> {
+    // setting up a watch (and reading the state) may block
+    let (mut events, state) = run_blocking(shared, |audio, _| {
+        let events = audio.subscribe()?;
+        Ok((events, audio.get_state()?))
+    })
+    .await?;
+    let (mut rd, mut wr) = tokio::io::split(socket);
+
+    with_timeout(protocol::write_frame(&mut wr, &Event::Volume(state))).await?;
+
+    let mut ping = tokio::time::interval(protocol::PING_INTERVAL);
+    let mut buf = [0; 64];
+    loop {
+        let event = tokio::select! {
+            Some(state) = events.next() => Event::Volume(state),
+            _ = ping.tick() => Event::Ping,
+            // clients aren't expected to send anything, so this only returns
+            // once they hang up
+            res = rd.read(&mut buf) => match res? {
+                0 => return Ok(()),
+                _ => continue,
+            },
+        };
+        with_timeout(protocol::write_frame(&mut wr, &event)).await?;
     }
 }
+
+fn handle_request(
+    req: Request,
+    audio: &VolumeController,
+    power: &PowerController,
+) -> anyhow::Result<Response> {
+    let resp = match req {
+        Request::GetVolume => Response::Volume(audio.get_master_volume()?),
+        Request::SetVolume(vol) => {
+            audio.set_master_volume(vol)?;
+            power.wake_screen()?;
+            Response::Ok
+        }
+        Request::GetMute => Response::Mute(audio.get_mute()?),
+        Request::SetMute(muted) => {
+            audio.set_mute(muted)?;
+            Response::Ok
+        }
+        Request::GetAppVolume(app) => Response::AppVolume(audio.get_app_volume(&app)?),
+        Request::SetAppVolume(app, vol) => {
+            let found = audio.set_app_volume(&app, vol)?;
+            if !found {
+                log::info!("{} isn't currently playing audio", app);
+            }
+            Response::AppVolumeSet(found)
+        }
+        Request::SetOutputDevice(device) => {
+            audio.set_default_endpoint(&device)?;
+            Response::Ok
+        }
+        // the connection is switched over before these ever get here
+        Request::Mirror(_) => return Err(anyhow::anyhow!("unexpected mirror request")),
+        Request::Subscribe => return Err(anyhow::anyhow!("unexpected subscribe request")),
+        Request::Ping => Response::Ok,
+        Request::WakeSpotify => {
+            log::info!("launching spotify");
+            crate::controllers::spotify::launch_client()?;
+            Response::Ok
+        }
+    };
+
+    Ok(resp)
+}
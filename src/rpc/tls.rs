@@ -0,0 +1,225 @@
+//! Optional TLS transport.
+//!
+//! Instead of relying on a CA, the server uses a self-signed certificate (see
+//! [`generate_cert`]), and clients pin the SHA-256 fingerprint of that
+//! certificate.
+
+use anyhow::anyhow;
+use anyhow::Context;
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::client::danger::ServerCertVerified;
+use rustls::client::danger::ServerCertVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::ServerName;
+use rustls::pki_types::UnixTime;
+use rustls::DigitallySignedStruct;
+use rustls::SignatureScheme;
+use sha2::Digest;
+use sha2::Sha256;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::TlsConnector;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Return the SHA-256 fingerprint of a (DER-encoded) certificate, formatted
+/// as colon-separated hex (e.g: `AB:CD:...`).
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Parse a fingerprint, ignoring case and any `:` separators.
+fn parse_fingerprint(s: &str) -> anyhow::Result<[u8; 32]> {
+    let hex = s.replace(':', "");
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(anyhow!("invalid TLS fingerprint: {:?}", s));
+    }
+
+    let mut fingerprint = [0; 32];
+    for (i, b) in fingerprint.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow!("invalid TLS fingerprint: {:?}", s))?;
+    }
+    Ok(fingerprint)
+}
+
+/// Generate a new self-signed certificate for `hostname`, returning the
+/// PEM-encoded certificate and private key.
+pub fn generate_cert(hostname: &str) -> anyhow::Result<(String, String)> {
+    let cert = rcgen::generate_simple_self_signed(vec![hostname.to_string()])?;
+    Ok((cert.cert.pem(), cert.key_pair.serialize_pem()))
+}
+
+/// Write a private key to `path` (replacing any existing file), such that
+/// only the current user can read it.
+///
+/// The file's permissions are locked down _before_ any key material is
+/// written, so there's no window where the key is readable by others.
+pub fn write_private_key(path: &Path, key_pem: &str) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    // permissions only get applied to newly created files
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("could not replace {:?}", path))
+        }
+        _ => {}
+    }
+
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    let mut file = opts
+        .open(path)
+        .with_context(|| format!("could not create {:?}", path))?;
+
+    // new files inherit the directory's ACL, so strip that, and only grant
+    // access to the current user
+    #[cfg(windows)]
+    {
+        let user = std::env::var("USERNAME").context("could not determine current user")?;
+        let res = std::process::Command::new("icacls")
+            .arg(path)
+            .args(["/inheritance:r", "/grant:r"])
+            .arg(format!("{}:F", user))
+            .output()
+            .context("failed to run `icacls`");
+        let res = res.and_then(|out| {
+            if out.status.success() {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "`icacls` failed: {}",
+                    String::from_utf8_lossy(&out.stdout).trim()
+                ))
+            }
+        });
+        if let Err(e) = res {
+            drop(file);
+            let _ = std::fs::remove_file(path);
+            return Err(e.context(format!("could not restrict access to {:?}", path)));
+        }
+    }
+
+    file.write_all(key_pem.as_bytes())
+        .with_context(|| format!("could not write {:?}", path))
+}
+
+/// Load a PEM-encoded certificate chain and private key from disk, returning
+/// an acceptor for the server side of TLS along with the certificate's
+/// fingerprint.
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> anyhow::Result<(TlsAcceptor, String)> {
+    let certs = rustls_pemfile::certs(&mut read_pem(cert_path)?.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("could not parse certificate {:?}", cert_path))?;
+    let cert = certs
+        .first()
+        .ok_or_else(|| anyhow!("no certificates found in {:?}", cert_path))?;
+    let fingerprint = fingerprint(cert);
+
+    let key = rustls_pemfile::private_key(&mut read_pem(key_path)?.as_slice())
+        .with_context(|| format!("could not parse private key {:?}", key_path))?
+        .ok_or_else(|| anyhow!("no private key found in {:?}", key_path))?;
+
+    let config = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok((TlsAcceptor::from(Arc::new(config)), fingerprint))
+}
+
+fn read_pem(path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("could not read {:?}", path))
+}
+
+/// Return a connector for the client side of TLS, which only trusts the
+/// server certificate with the given fingerprint.
+pub fn connector(fingerprint: &str) -> anyhow::Result<TlsConnector> {
+    let verifier = PinnedCertVerifier {
+        fingerprint: parse_fingerprint(fingerprint)?,
+        provider: provider(),
+    };
+
+    let config = rustls::ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Trusts a single certificate (identified by its fingerprint), regardless of
+/// who signed it or what name it was issued for.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity).as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "server certificate fingerprint ({}) doesn't match the pinned fingerprint",
+                fingerprint(end_entity)
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
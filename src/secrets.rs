@@ -0,0 +1,152 @@
+//! Spotify secrets (the client secret and OAuth token) kept in the OS keyring
+//! (i.e: the Secret Service on Linux, or the Credential Manager on Windows),
+//! instead of in plaintext files.
+
+use crate::config;
+use anyhow::anyhow;
+use anyhow::Context;
+use std::path::Path;
+
+/// Service name all of our keyring entries are filed under.
+const SERVICE: &str = "music-transfer";
+
+/// Somewhere to stash secrets.
+pub trait SecretStore {
+    /// Returns `None` if there's no such secret.
+    fn get(&self, key: &str) -> anyhow::Result<Option<String>>;
+    fn set(&self, key: &str, secret: &str) -> anyhow::Result<()>;
+}
+
+/// The OS keyring.
+pub struct Keyring;
+
+impl SecretStore for Keyring {
+    fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        match keyring::Entry::new(SERVICE, key)?.get_password() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e).context("could not read from the OS keyring"),
+        }
+    }
+
+    fn set(&self, key: &str, secret: &str) -> anyhow::Result<()> {
+        keyring::Entry::new(SERVICE, key)?
+            .set_password(secret)
+            .context("could not write to the OS keyring")
+    }
+}
+
+/// Stands in for the OS keyring in tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStore(std::sync::Mutex<std::collections::HashMap<String, String>>);
+
+#[cfg(test)]
+impl SecretStore for MemoryStore {
+    fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.0.lock().unwrap().get(key).cloned())
+    }
+
+    fn set(&self, key: &str, secret: &str) -> anyhow::Result<()> {
+        self.0.lock().unwrap().insert(key.into(), secret.into());
+        Ok(())
+    }
+}
+
+/// Key the client secret for the given spotify app is stored under.
+pub fn client_secret_key(client_id: &str) -> String {
+    format!("spotify_client_secret/{}", client_id)
+}
+
+/// Key the (JSON-encoded) OAuth token for the given spotify app is stored
+/// under.
+pub fn token_key(client_id: &str) -> String {
+    format!("spotify_token/{}", client_id)
+}
+
+/// Copy the client secret from the config into `store`.
+pub fn migrate_client_secret(
+    store: &dyn SecretStore,
+    creds: &config::SpotifyCreds,
+) -> anyhow::Result<()> {
+    let secret = creds
+        .spotify_client_secret
+        .as_deref()
+        .ok_or_else(|| anyhow!(r#"no "spotify_client_secret" in config to migrate"#))?;
+    store.set(&client_secret_key(&creds.spotify_client_id), secret)
+}
+
+/// Move the token cached at `cache_path` into `store`, deleting the cache file
+/// once it's safely stored.
+pub fn migrate_token(
+    store: &dyn SecretStore,
+    client_id: &str,
+    cache_path: &Path,
+) -> anyhow::Result<()> {
+    let token = std::fs::read_to_string(cache_path)
+        .with_context(|| format!("could not read token cache {:?}", cache_path))?;
+    // don't stash (and then delete!) something that isn't actually a token
+    serde_json::from_str::<rspotify::Token>(&token)
+        .with_context(|| format!("{:?} doesn't contain a valid token", cache_path))?;
+
+    store.set(&token_key(client_id), &token)?;
+    std::fs::remove_file(cache_path)
+        .with_context(|| format!("could not delete token cache {:?}", cache_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controllers::spotify::mock;
+    use crate::controllers::spotify::mock::MockSpotify;
+
+    fn temp_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "music-transfer-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn migrate_token_moves_cache_into_store() {
+        let token = serde_json::to_string(&rspotify::Token::default()).unwrap();
+        let path = temp_file("token", &token);
+
+        let store = MemoryStore::default();
+        migrate_token(&store, "some-client", &path).unwrap();
+
+        assert_eq!(store.get(&token_key("some-client")).unwrap(), Some(token));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn migrate_token_leaves_bogus_cache_alone() {
+        let path = temp_file("bogus-token", "not a token");
+
+        let store = MemoryStore::default();
+        assert!(migrate_token(&store, "some-client", &path).is_err());
+
+        assert_eq!(store.get(&token_key("some-client")).unwrap(), None);
+        assert!(path.exists());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn migrate_client_secret_copies_secret_into_store() {
+        let creds = config::SpotifyCreds {
+            keyring: true,
+            ..mock::creds(&MockSpotify::spawn().await)
+        };
+
+        let store = MemoryStore::default();
+        migrate_client_secret(&store, &creds).unwrap();
+
+        assert_eq!(
+            store.get(&client_secret_key("some-client")).unwrap(),
+            Some("hunter2".into())
+        );
+    }
+}
@@ -0,0 +1,611 @@
+//! Transferring audio playback + settings between two computers.
+
+use crate::config;
+use crate::controllers::spotify::LoginRequired;
+use crate::controllers::spotify::SpotifyWrapper;
+use crate::controllers::spotify::DEFAULT_DEVICE_WAIT;
+use crate::controllers::volume::VolumeController;
+use crate::rpc::client::AudioClient;
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+
+/// How often [`Transferer::keep_alive`] should be called while idle. The
+/// remote server drops connections which have been idle for 30 seconds.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncAudioTo {
+    Remote,
+    Local,
+}
+
+impl std::fmt::Display for SyncAudioTo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncAudioTo::Remote => write!(f, "remote"),
+            SyncAudioTo::Local => write!(f, "local"),
+        }
+    }
+}
+
+impl std::str::FromStr for SyncAudioTo {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let res = match s {
+            "local" => SyncAudioTo::Local,
+            "remote" => SyncAudioTo::Remote,
+            _ => return Err("sync-audio-from must be one of 'local' or 'remote'"),
+        };
+        Ok(res)
+    }
+}
+
+/// Which computer to transfer audio to, as passed to `transfer`.
+#[derive(Debug, Clone, Copy)]
+pub enum TransferTarget {
+    To(SyncAudioTo),
+    /// Whichever computer audio _isn't_ currently playing on
+    Toggle,
+}
+
+impl std::str::FromStr for TransferTarget {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toggle" => Ok(TransferTarget::Toggle),
+            _ => s.parse().map(TransferTarget::To),
+        }
+    }
+}
+
+/// Performs transfers, holding on to the system volume controller, remote
+/// server connection, and spotify client between transfers (so that
+/// long-running commands don't have to reconnect / re-authenticate each time).
+///
+/// Everything is initialized lazily, the first time it's needed.
+pub struct Transferer {
+    system_audio: config::SystemAudio,
+    volume_sync: Option<config::VolumeSync>,
+    spotify_creds: Option<config::SpotifyCreds>,
+    spotify_transfer: Option<config::SpotifyTransfer>,
+    spotify_token_cache_path: String,
+    interactive_login: bool,
+
+    audio: Option<VolumeController>,
+    client: Option<AudioClient>,
+    spotify: Option<SpotifyWrapper>,
+}
+
+impl Transferer {
+    pub fn new(
+        system_audio: config::SystemAudio,
+        volume_sync: Option<config::VolumeSync>,
+        spotify_creds: Option<config::SpotifyCreds>,
+        spotify_transfer: Option<config::SpotifyTransfer>,
+        spotify_token_cache_path: String,
+    ) -> Transferer {
+        Transferer {
+            system_audio,
+            volume_sync,
+            spotify_creds,
+            spotify_transfer,
+            spotify_token_cache_path,
+            interactive_login: false,
+            audio: None,
+            client: None,
+            spotify: None,
+        }
+    }
+
+    /// Prompt the user to log in to spotify if needed (instead of failing).
+    pub fn with_interactive_login(mut self) -> Transferer {
+        self.interactive_login = true;
+        self
+    }
+
+    /// Initialize everything required for the given kind of transfer up front
+    /// (instead of waiting for the first transfer).
+    ///
+    /// Failing to connect to the remote server isn't an error, since it may
+    /// simply not be running yet.
+    pub async fn warm_up(&mut self, spotify: bool, sync_volume: bool) -> anyhow::Result<()> {
+        if sync_volume {
+            self.init_audio()?;
+            if let Err(e) = self.connect().await {
+                log::warn!("could not connect to remote server (yet): {:#}", e);
+            }
+        }
+
+        if spotify {
+            self.init_spotify().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Ping the remote server (if connected), so that the connection doesn't
+    /// idle out. If the connection has been lost, the next transfer
+    /// reconnects.
+    pub async fn keep_alive(&mut self) {
+        if let Some(client) = &mut self.client {
+            if let Err(e) = client.ping().await {
+                log::warn!("lost connection to remote server: {:#}", e);
+                self.client = None;
+            }
+        }
+    }
+
+    pub async fn transfer(
+        &mut self,
+        target: SyncAudioTo,
+        spotify: bool,
+        sync_volume: bool,
+    ) -> anyhow::Result<()> {
+        if !spotify && !sync_volume {
+            log::warn!("executed 'tranfer' without including transfer option. doing nothing...")
+        }
+
+        if sync_volume {
+            let res = self.sync_volume(target).await;
+            if res.is_err() {
+                // the connection may have been left mid-request, so start afresh next time
+                self.client = None;
+            }
+            res?;
+        }
+
+        if spotify {
+            let config::SpotifyTransfer {
+                spotify_name_remote,
+                spotify_name_local,
+                device_wait_secs,
+                wake_remote,
+                when_idle,
+            } = {
+                self.spotify_transfer
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!(r#"missing "spotify_transfer" from config"#))?
+            };
+            let target_device = match target {
+                SyncAudioTo::Local => spotify_name_local,
+                SyncAudioTo::Remote => spotify_name_remote,
+            }
+            .clone();
+            let device_wait = device_wait_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_DEVICE_WAIT);
+            let when_idle = *when_idle;
+
+            if let (SyncAudioTo::Remote, true) = (target, *wake_remote) {
+                // if this doesn't work out, the device may well be online anyways
+                if let Err(e) = self.wake_remote_spotify().await {
+                    log::warn!("could not wake spotify on the remote: {:#}", e);
+                }
+            }
+
+            self.init_spotify()
+                .await?
+                .transfer_playback(&target_device, sync_volume, device_wait, when_idle)
+                .await?
+        }
+
+        Ok(())
+    }
+
+    /// Figure out which computer audio should be transferred to (i.e: the one
+    /// it isn't currently playing on), based on the active spotify device
+    /// and / or which computer is audible.
+    ///
+    /// Fails if it's unclear where audio is currently playing.
+    pub async fn toggle_target(
+        &mut self,
+        spotify: bool,
+        sync_volume: bool,
+    ) -> anyhow::Result<SyncAudioTo> {
+        let from_spotify = if spotify {
+            self.spotify_playing_on().await?
+        } else {
+            None
+        };
+
+        let from_volume = if sync_volume {
+            let res = self.audible_on().await;
+            if res.is_err() {
+                self.client = None;
+            }
+            res?
+        } else {
+            None
+        };
+
+        let playing_on = match (from_spotify, from_volume) {
+            (Some(a), Some(b)) if a != b => {
+                return Err(anyhow::anyhow!(
+                    "can't tell which way to transfer: spotify is playing on the {} computer, but only the {} computer is audible",
+                    a,
+                    b
+                ))
+            }
+            (Some(playing_on), _) | (None, Some(playing_on)) => playing_on,
+            (None, None) => {
+                return Err(anyhow::anyhow!(
+                    "can't tell which way to transfer: {}. pass `local` or `remote` explicitly",
+                    match (spotify, sync_volume) {
+                        (false, false) => "neither --spotify nor --sync-volume was passed",
+                        (true, false) => "spotify isn't playing on either computer",
+                        (false, true) => "both computers are audible (or neither is)",
+                        (true, true) => "spotify isn't playing on either computer, and both computers are audible (or neither is)",
+                    }
+                ))
+            }
+        };
+
+        let target = match playing_on {
+            SyncAudioTo::Local => SyncAudioTo::Remote,
+            SyncAudioTo::Remote => SyncAudioTo::Local,
+        };
+        log::info!("audio is playing on the {} computer", playing_on);
+        Ok(target)
+    }
+
+    /// Which computer the active spotify device belongs to (if either).
+    async fn spotify_playing_on(&mut self) -> anyhow::Result<Option<SyncAudioTo>> {
+        let config::SpotifyTransfer {
+            spotify_name_remote,
+            spotify_name_local,
+            ..
+        } = self
+            .spotify_transfer
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!(r#"missing "spotify_transfer" from config"#))?;
+        let (name_local, name_remote) = (spotify_name_local.clone(), spotify_name_remote.clone());
+
+        let device = match self.init_spotify().await?.active_device().await? {
+            Some(device) => device,
+            None => return Ok(None),
+        };
+        let playing_on = if device.name == name_local {
+            Some(SyncAudioTo::Local)
+        } else if device.name == name_remote {
+            Some(SyncAudioTo::Remote)
+        } else {
+            log::info!("spotify is playing on some other device ({})", device.name);
+            None
+        };
+        Ok(playing_on)
+    }
+
+    /// Which computer is audible (i.e: has non-zero volume, and isn't muted),
+    /// if only one of them is.
+    async fn audible_on(&mut self) -> anyhow::Result<Option<SyncAudioTo>> {
+        self.init_audio()?;
+        self.connect().await?;
+
+        let audio = self.audio.as_ref().unwrap();
+        let client = self.client.as_mut().unwrap();
+
+        let local = audio.get_master_volume()? > 0.0 && !audio.get_mute()?;
+        let remote = client
+            .get_remote_volume()
+            .await
+            .context("error communicating with remote server")?
+            > 0.0
+            && !client
+                .get_remote_mute()
+                .await
+                .context("error communicating with remote server")?;
+
+        let audible_on = match (local, remote) {
+            (true, false) => Some(SyncAudioTo::Local),
+            (false, true) => Some(SyncAudioTo::Remote),
+            _ => None,
+        };
+        Ok(audible_on)
+    }
+
+    fn init_audio(&mut self) -> anyhow::Result<&VolumeController> {
+        if self.audio.is_none() {
+            self.audio = Some(
+                VolumeController::new(&self.system_audio)
+                    .context("could not init system volume controller")?,
+            );
+        }
+        Ok(self.audio.as_ref().unwrap())
+    }
+
+    async fn init_spotify(&mut self) -> anyhow::Result<&mut SpotifyWrapper> {
+        if self.spotify.is_none() {
+            let spotify_creds = self
+                .spotify_creds
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!(r#"missing "spotify_creds" from config"#))?;
+
+            let cache_path = &self.spotify_token_cache_path;
+            let spotify = match SpotifyWrapper::new(cache_path, spotify_creds).await {
+                Err(e) if self.interactive_login && e.is::<LoginRequired>() => {
+                    log::warn!("{}", e);
+                    SpotifyWrapper::login(cache_path, spotify_creds).await?
+                }
+                res => res?,
+            };
+            self.spotify = Some(spotify);
+        }
+        Ok(self.spotify.as_mut().unwrap())
+    }
+
+    /// Connect to the remote server, reusing the existing connection if it's
+    /// still alive.
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        let volume_sync = self
+            .volume_sync
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!(r#"missing "volume_sync" from config"#))?;
+
+        if let Some(client) = &mut self.client {
+            match client.ping().await {
+                Ok(()) => return Ok(()),
+                Err(e) => log::info!("reconnecting to remote server ({:#})", e),
+            }
+        }
+
+        self.client = None;
+        self.client = Some(crate::connect_remote(volume_sync).await?);
+        Ok(())
+    }
+
+    async fn wake_remote_spotify(&mut self) -> anyhow::Result<()> {
+        self.connect().await?;
+        let res = self.client.as_mut().unwrap().wake_remote_spotify().await;
+        if res.is_err() {
+            self.client = None;
+        }
+        res
+    }
+
+    async fn sync_volume(&mut self, target: SyncAudioTo) -> anyhow::Result<()> {
+        // make sure we can actually do system audio control before doing any networking
+        self.init_audio()?;
+        self.connect().await?;
+
+        let audio = self.audio.as_ref().unwrap();
+        let client = self.client.as_mut().unwrap();
+        let config::VolumeSync {
+            sync_apps,
+            output_device_remote,
+            output_device_local,
+            ..
+        } = self.volume_sync.as_ref().unwrap();
+
+        // switch output devices first, so that the synced volume gets applied to the
+        // right device
+        match (target, output_device_local, output_device_remote) {
+            (SyncAudioTo::Local, Some(device), _) => {
+                log::info!("switching local output device to {}", device);
+
+                audio.set_default_endpoint(device)?;
+            }
+            (SyncAudioTo::Remote, _, Some(device)) => {
+                log::info!("switching remote output device to {}", device);
+
+                client
+                    .set_remote_output_device(device)
+                    .await
+                    .context("error communicating with remote server")?;
+            }
+            _ => {}
+        }
+
+        match target {
+            SyncAudioTo::Local => {
+                let new_vol = client
+                    .get_remote_volume()
+                    .await
+                    .context("error communicating with remote server")?;
+                let muted = client
+                    .get_remote_mute()
+                    .await
+                    .context("error communicating with remote server")?;
+
+                log::info!("setting local volume to {} (muted: {})", new_vol, muted);
+
+                audio.set_master_volume(new_vol)?;
+                audio.set_mute(muted)?;
+            }
+            SyncAudioTo::Remote => {
+                let current_volume = audio.get_master_volume()?;
+                let muted = audio.get_mute()?;
+
+                log::info!(
+                    "setting remote volume to {} (muted: {})",
+                    current_volume,
+                    muted
+                );
+
+                client
+                    .set_remote_volume(current_volume)
+                    .await
+                    .context("error communicating with remote server")?;
+                client
+                    .set_remote_mute(muted)
+                    .await
+                    .context("error communicating with remote server")?;
+            }
+        }
+
+        for app in sync_apps {
+            // per-app volume is best-effort, and shouldn't get in the way of the rest
+            // of the transfer
+            if let Err(e) = sync_app_volume(audio, client, target, app).await {
+                log::warn!("could not sync volume of {}: {:#}", app, e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn sync_app_volume(
+    audio: &VolumeController,
+    client: &mut AudioClient,
+    target: SyncAudioTo,
+    app: &str,
+) -> anyhow::Result<()> {
+    match target {
+        SyncAudioTo::Local => {
+            let new_vol = match client.get_remote_app_volume(app).await? {
+                Some(vol) => vol,
+                None => {
+                    log::info!("{} isn't playing audio on the remote, skipping", app);
+                    return Ok(());
+                }
+            };
+
+            log::info!("setting local volume of {} to {}", app, new_vol);
+
+            if !audio.set_app_volume(app, new_vol)? {
+                log::info!("{} isn't playing audio locally, skipping", app);
+            }
+        }
+        SyncAudioTo::Remote => {
+            let current_volume = match audio.get_app_volume(app)? {
+                Some(vol) => vol,
+                None => {
+                    log::info!("{} isn't playing audio locally, skipping", app);
+                    return Ok(());
+                }
+            };
+
+            log::info!("setting remote volume of {} to {}", app, current_volume);
+
+            if !client.set_remote_app_volume(app, current_volume).await? {
+                log::info!("{} isn't playing audio on the remote, skipping", app);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controllers::spotify::mock;
+    use crate::controllers::spotify::mock::MockSpotify;
+    use crate::controllers::spotify::WhenIdle;
+    use crate::controllers::volume::mock::MockCall;
+    use crate::controllers::volume::mock::MockVolume;
+    use crate::controllers::volume::VolumeBackend;
+    use crate::rpc::server::AudioServer;
+
+    /// Set up a [`Transferer`] which talks to `spotify`, and an in-process
+    /// remote server backed by `remote`. The local computer uses the mock
+    /// volume backend (which starts off at 0.5).
+    async fn transferer(spotify: &MockSpotify, remote: &MockVolume) -> Transferer {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = AudioServer::new(0, VolumeController::from_backend(remote.clone()));
+        tokio::spawn(server.serve(listener));
+
+        let cache_path = mock::seed_token_cache();
+
+        let mut transferer = Transferer::new(
+            config::SystemAudio {
+                backend: Some(crate::controllers::volume::Backend::Mock),
+                ..Default::default()
+            },
+            Some(config::VolumeSync {
+                remote_host: Some("127.0.0.1".into()),
+                remote_port: Some(port),
+                remote_name: None,
+                sync_apps: Vec::new(),
+                output_device_remote: None,
+                output_device_local: None,
+                psk: None,
+                tls_fingerprint: None,
+            }),
+            Some(mock::creds(spotify)),
+            Some(config::SpotifyTransfer {
+                spotify_name_remote: "remote".into(),
+                spotify_name_local: "local".into(),
+                device_wait_secs: Some(1),
+                wake_remote: false,
+                when_idle: WhenIdle::Activate,
+            }),
+            cache_path.to_str().unwrap().into(),
+        );
+        // load the cached token up front, so that the cache can be cleaned up
+        let res = transferer.warm_up(true, false).await;
+        std::fs::remove_file(cache_path).unwrap();
+        res.unwrap();
+
+        transferer
+    }
+
+    async fn mock_spotify(playing_on: &str) -> MockSpotify {
+        let spotify = MockSpotify::spawn().await;
+        spotify.add_device("local-id", "local", 30);
+        spotify.add_device("remote-id", "remote", 80);
+        spotify.play_on(playing_on);
+        spotify
+    }
+
+    /// Runs the whole of `transfer remote --spotify --sync-volume`, against a
+    /// mock spotify API and an in-process remote server.
+    #[tokio::test]
+    async fn transfer_to_remote() {
+        let spotify = mock_spotify("local-id").await;
+        let remote = MockVolume::new(0.9);
+        let mut transferer = transferer(&spotify, &remote).await;
+
+        transferer
+            .transfer(SyncAudioTo::Remote, true, true)
+            .await
+            .unwrap();
+
+        assert!(remote.calls().contains(&MockCall::SetMasterVolume(0.5)));
+        assert_eq!(spotify.playback(), Some(("remote-id".into(), true)));
+        assert_eq!(spotify.volume("remote-id"), Some(30));
+    }
+
+    #[tokio::test]
+    async fn toggle_picks_other_computer() {
+        let spotify = mock_spotify("remote-id").await;
+        let remote = MockVolume::new(0.9);
+        let mut transferer = transferer(&spotify, &remote).await;
+
+        transferer.init_audio().unwrap().set_mute(true).unwrap();
+
+        for (spotify, sync_volume) in [(true, false), (false, true), (true, true)] {
+            let target = transferer
+                .toggle_target(spotify, sync_volume)
+                .await
+                .unwrap();
+            assert_eq!(target, SyncAudioTo::Local);
+        }
+    }
+
+    #[tokio::test]
+    async fn toggle_refuses_when_ambiguous() {
+        let spotify = mock_spotify("local-id").await;
+        let remote = MockVolume::new(0.9);
+        let mut transferer = transferer(&spotify, &remote).await;
+
+        // both computers are audible
+        let err = transferer.toggle_target(false, true).await.unwrap_err();
+        assert!(err.to_string().contains("can't tell"), "{}", err);
+
+        // spotify is playing locally, but only the remote is audible
+        transferer.init_audio().unwrap().set_mute(true).unwrap();
+        let err = transferer.toggle_target(true, true).await.unwrap_err();
+        assert!(err.to_string().contains("can't tell"), "{}", err);
+
+        // neither is audible
+        remote.set_master_volume(0.0).unwrap();
+        assert!(transferer.toggle_target(false, true).await.is_err());
+    }
+}